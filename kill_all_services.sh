@@ -3,7 +3,8 @@ docker kill url_extractor
 docker kill post_sentiment_calculator
 docker kill post_college_filter
 docker kill post_sentiment_filter
-docker kill score_extractor
+docker kill score_extractor_0
+docker kill score_extractor_1
 docker kill comment_sentiment_extractor
 docker kill best_meme_filter
 docker kill post_average_filter
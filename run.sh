@@ -16,11 +16,11 @@ RABBITMQ_HOST=172.21.0.2 cargo run --release --bin post_sentiment_calculator &
 RABBITMQ_HOST=172.21.0.2 PRODUCERS=2 cargo run --release --bin post_sentiment_filter &
 RABBITMQ_HOST=172.21.0.2 cargo run --release --bin url_extractor &
 RABBITMQ_HOST=172.21.0.2 cargo run --release --bin url_extractor &
-RABBITMQ_HOST=172.21.0.2 cargo run --release --bin best_meme_filter &
+RABBITMQ_HOST=172.21.0.2 PRODUCERS=2 cargo run --release --bin best_meme_filter &
 
 RABBITMQ_HOST=172.21.0.2 cargo run --release --bin comment_college_filter &
-RABBITMQ_HOST=172.21.0.2 cargo run --release --bin post_college_filter &
-RABBITMQ_HOST=172.21.0.2 cargo run --release --bin post_average_filter &
+RABBITMQ_HOST=172.21.0.2 PRODUCERS=2 cargo run --release --bin post_college_filter &
+RABBITMQ_HOST=172.21.0.2 PRODUCERS=2 cargo run --release --bin post_average_filter &
 
 RABBITMQ_HOST=172.21.0.2 CONSUMERS=2 cargo run --release --bin post_producer &
 RABBITMQ_HOST=172.21.0.2 CONSUMERS=2 cargo run --release --bin comment_producer &
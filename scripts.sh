@@ -1,6 +1,7 @@
 #!/bin/bash
 
 RABBITMQ_HOST=rabbitmq
+SCORE_EXTRACTORS=${SCORE_EXTRACTORS:-1}
 
 echo "Connecting to $RABBITMQ_HOST..."
 
@@ -19,16 +20,22 @@ do
 done
 
 
-QUEUES="tp2.posts.url_src tp2.posts.urls tp2.posts.urls.id tp2.posts.college_id tp2.posts.above_average tp2.posts.score_src tp2.posts.mean tp2.posts.mean.result tp2.posts.sentiment tp2.posts.sentiment.filtered tp2.posts.sentiment.best tp2.comments.sentiment_src tp2.comments.college_src tp2.results tp2.data.save"
+QUEUES="tp2.posts.url_src tp2.posts.urls tp2.posts.above_average tp2.posts.mean tp2.posts.sentiment tp2.posts.sentiment.filtered tp2.comments.sentiment_src tp2.comments.college_src tp2.results tp2.progress tp2.data.save"
 for queue_name in ${QUEUES}
 do
 rabbitmqadmin -H $RABBITMQ_HOST declare queue auto_delete=false durable=false name=$queue_name
 done;
-rabbitmqadmin -H $RABBITMQ_HOST declare exchange type=fanout name=tp2.posts
-rabbitmqadmin -H $RABBITMQ_HOST declare exchange type=fanout name=tp2.comments
+rabbitmqadmin -H $RABBITMQ_HOST declare exchange type=topic name=tp2.posts
+rabbitmqadmin -H $RABBITMQ_HOST declare exchange type=topic name=tp2.comments
+rabbitmqadmin -H $RABBITMQ_HOST declare exchange type=fanout name=tp2.progress
 
-rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.posts destination=tp2.posts.college_src
-rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.posts destination=tp2.posts.score_src
-rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.posts destination=tp2.posts.url_src
-rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.comments destination=tp2.comments.sentiment_src
-rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.comments destination=tp2.comments.college_src
+rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.posts destination=tp2.posts.college_src routing_key=#.college.#
+for replica in $(seq 0 $((SCORE_EXTRACTORS - 1)))
+do
+rabbitmqadmin -H $RABBITMQ_HOST declare queue auto_delete=false durable=false name=tp2.posts.score_src.$replica
+rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.posts destination=tp2.posts.score_src.$replica routing_key=#.score_$replica.#
+done;
+rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.posts destination=tp2.posts.url_src routing_key=#.url.#
+rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.comments destination=tp2.comments.sentiment_src routing_key=#.sentiment.#
+rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.comments destination=tp2.comments.college_src routing_key=#.college.#
+rabbitmqadmin -H $RABBITMQ_HOST declare binding source=tp2.progress destination=tp2.progress
@@ -1,13 +1,13 @@
-use std::ops::Add;
 use amiquip::Result;
-use log::{debug, error, info, warn};
-use tp2::messages::Message;
+use log::{debug, warn};
+use std::collections::{BTreeMap, HashMap};
+use tp2::messages::{MemeCandidate, Message};
 use tp2::middleware::message_processor::MessageProcessor;
 use tp2::middleware::service::{init, RabbitService};
 use tp2::middleware::RabbitExchange;
-use tp2::{
-    Config, POST_EXTRACTED_URL_QUEUE_NAME, POST_SENTIMENT_MEAN_QUEUE_NAME, RESULTS_QUEUE_NAME,
-};
+use tp2::protocol::RankedMeme;
+use tp2::top_k::TopK;
+use tp2::{Config, POST_EXTRACTED_URL_QUEUE_NAME, RESULTS_QUEUE_NAME};
 use tp2::health_checker::health_answerer::HealthAnswerer;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -34,46 +34,68 @@ fn main() -> Result<()> {
 }
 
 fn run_service(config: Config) -> Result<()> {
-    info!("Getting best meme id");
-    let mut id_processor = BestMemeIdConsumer::default();
-    let mut subservice = RabbitService::new_subservice(config.clone(), &mut id_processor);
-    subservice.run_once(POST_SENTIMENT_MEAN_QUEUE_NAME, None)?;
-    info!("Got sentiment {:?}", id_processor.best_meme_id_sentiment);
-    let best_meme_id = id_processor.best_meme_id_sentiment.0;
-    // FIX: Seems that closing and opening a connection so fast crashes the app, putting a sleep
-    std::thread::sleep(std::time::Duration::from_secs(1));
-    let consumer_transaction_log_path = config.transaction_log_path.clone().add(".subservice");
-    info!("Getting best meme");
-    let mut processor = BestMemeFilter::new(best_meme_id);
-    let mut service = RabbitService::new(config, &mut processor);
-    service.run(POST_EXTRACTED_URL_QUEUE_NAME, None)?;
-    std::fs::remove_file(&consumer_transaction_log_path);
-    Ok(())
+    let processor = BestMemeFilter {
+        best_memes: config.best_memes,
+        candidates: config.best_meme_candidates.max(config.best_memes),
+        ..Default::default()
+    };
+    let mut service = RabbitService::new(config, processor);
+    service.run(POST_EXTRACTED_URL_QUEUE_NAME, None)
 }
 
+/// Post urls and the best meme candidates arrive through the same queue. Urls are kept until
+/// the candidates are known, then only the urls of the candidates. The best memes of each group
+/// are its best candidates with a url.
+#[derive(Clone, Default)]
 struct BestMemeFilter {
-    best_meme_id: String,
-    best_meme_url: String,
+    best_memes: usize,
+    candidates: usize,
+    rankings: Option<HashMap<String, TopK<MemeCandidate>>>,
+    urls: HashMap<String, String>,
 }
 
 impl BestMemeFilter {
-    pub fn new(best_meme_id: String) -> Self {
-        Self {
-            best_meme_id,
-            best_meme_url: "".to_string(),
-        }
+    fn best_memes(&self, ranking: &TopK<MemeCandidate>) -> Vec<RankedMeme> {
+        ranking
+            .clone()
+            .into_ranking()
+            .into_iter()
+            .filter_map(|(candidate, sentiment)| match self.urls.get(&candidate.id) {
+                Some(url) => Some(RankedMeme {
+                    id: candidate.id,
+                    url: url.clone(),
+                    sentiment,
+                }),
+                None => {
+                    debug!("Passing over candidate {} without url", candidate.id);
+                    None
+                }
+            })
+            .take(self.best_memes)
+            .collect()
     }
 }
 
 impl MessageProcessor for BestMemeFilter {
-    type State = ();
+    type State = (Option<HashMap<String, TopK<MemeCandidate>>>, HashMap<String, String>);
     fn process_message(&mut self, message: Message) -> Option<Message> {
         match message {
-            Message::PostUrl(id, url) => {
-                if id == self.best_meme_id {
-                    self.best_meme_url = url;
+            Message::PostUrl(_, id, url) => {
+                if self.rankings.as_ref().is_none_or(|rankings| is_candidate(rankings, &id)) {
+                    self.urls.insert(id, url);
                 }
             }
+            Message::BestMemeCandidates(candidates) => {
+                debug!("Got best meme candidates {:?}", candidates);
+                let rankings = self.rankings.get_or_insert_with(HashMap::new);
+                for (group, candidates) in candidates {
+                    let ranking = rankings.entry(group).or_insert_with(|| TopK::new(self.candidates));
+                    for (candidate, sentiment) in candidates {
+                        ranking.push(candidate, sentiment);
+                    }
+                }
+                self.urls.retain(|id, _| is_candidate(rankings, id));
+            }
             _ => {
                 warn!("Invalid message arrived");
             }
@@ -81,12 +103,19 @@ impl MessageProcessor for BestMemeFilter {
         None
     }
 
-    fn on_stream_finished(&self) -> Option<Message> {
-        debug!("Sending best meme url: {}", self.best_meme_url);
-        Some(Message::PostUrl(
-            self.best_meme_id.clone(),
-            self.best_meme_url.clone(),
-        ))
+    fn on_stream_finished(&self) -> Vec<Message> {
+        let mut best_memes: BTreeMap<String, Vec<RankedMeme>> = self
+            .rankings
+            .iter()
+            .flatten()
+            .map(|(group, ranking)| (group.clone(), self.best_memes(ranking)))
+            .collect();
+        if best_memes.is_empty() {
+            // Jobs without sentiments still get an empty ranking
+            best_memes.insert(String::new(), vec![]);
+        }
+        debug!("Sending best memes: {:?}", best_memes);
+        vec![Message::BestMemes(best_memes)]
     }
 
     fn send_process_output<E: RabbitExchange>(
@@ -95,39 +124,18 @@ impl MessageProcessor for BestMemeFilter {
         message: Message,
     ) -> Result<()> {
         exchange.send_with_key(&message, RESULTS_QUEUE_NAME)?;
-        exchange.send_with_key(&Message::Confirmed, RESULTS_QUEUE_NAME)
-
+        exchange.send_with_key(&message.confirm(), RESULTS_QUEUE_NAME)
     }
-}
-// Should I use a heap of best memes ids in case the best one is missing?
 
-struct BestMemeIdConsumer {
-    best_meme_id_sentiment: (String, f32),
-}
+    fn get_state(&self) -> Option<Self::State> {
+        Some((self.rankings.clone(), self.urls.clone()))
+    }
 
-impl Default for BestMemeIdConsumer {
-    fn default() -> Self {
-        let best_meme_id_sentiment = ("".to_string(), f32::MIN);
-        Self {
-            best_meme_id_sentiment,
-        }
+    fn set_state(&mut self, state: Self::State) {
+        (self.rankings, self.urls) = state;
     }
 }
 
-impl MessageProcessor for BestMemeIdConsumer {
-    type State = ();
-
-    fn process_message(&mut self, message: Message) -> Option<Message> {
-        match message {
-            Message::PostIdSentiment(id, sentiment) => {
-                if sentiment > self.best_meme_id_sentiment.1 {
-                    self.best_meme_id_sentiment = (id, sentiment);
-                }
-            }
-            _ => {
-                error!("Invalid message arrived");
-            }
-        }
-        None
-    }
+fn is_candidate(rankings: &HashMap<String, TopK<MemeCandidate>>, id: &str) -> bool {
+    rankings.values().flat_map(TopK::iter).any(|candidate| candidate.id == id)
 }
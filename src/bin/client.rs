@@ -1,112 +1,463 @@
-use std::{io, thread};
-use std::io::{BufReader, Read, Write};
+use std::{fmt, io, thread};
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
 use std::net::Shutdown::Both;
 use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::Duration;
-use envconfig::Envconfig;
-use std::str;
-
-fn main() {
-    thread::sleep(Duration::from_secs(30));
-    println!("Client started");
-    let config = ClientConfig::init_from_env().expect("Failed to read env configuration");
-    let mut connection = TcpStream::connect(config.server_address).expect("Failed to connect to server");
-    let _ = send_file(&mut connection, &config.posts_path, config.chunk_size);
-    let _ = send_file(&mut connection, &config.comments_path, config.chunk_size);
+use clap::{Args, Parser, Subcommand};
+use flate2::read::GzEncoder;
+use tp2::jobs::JobResults;
+use tp2::output::{self, OutputFormat};
+use tp2::protocol::{self, Compression, ErrorCode, FileKind, Frame, GroupBy, InputFormat, JobStatus, Progress, Query, QueryResult, UploadEncoding, PROTOCOL_VERSION};
+
+const MAX_RESUME_TRIES: u64 = 5;
+const SEC_BETWEEN_RESUME_TRIES: u64 = 5;
+/// Chunks sent without waiting for their ack
+const ACK_WINDOW: usize = 64;
+const SEC_BETWEEN_POLLS: u64 = 10;
+/// Wait before the first connection retry, doubled after every failed try
+const FIRST_CONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The job finished with every requested query
+const EXIT_SUCCESS: u8 = 0;
+/// Connection or local errors
+const EXIT_FAILURE: u8 = 1;
+/// The job failed or was cancelled
+const EXIT_JOB_FAILED: u8 = 3;
+/// The job reached its deadline and some queries are missing
+const EXIT_PARTIAL_RESULTS: u8 = 4;
+/// Errors sent by the server exit with this plus their error code
+const EXIT_SERVER_ERROR_BASE: u8 = 10;
+
+/// Submits jobs to the memes server and fetches their results
+#[derive(Parser)]
+#[command(after_help = "Exit codes: 0 success, 1 connection or local error, 2 invalid arguments, \
+3 job failed or cancelled, 4 partial results, 10 + code for server errors")]
+struct Cli {
+    #[arg(long, env = "SERVER_ADDRESS", default_value = "server:9090")]
+    server: String,
+    /// Connection attempts before giving up, waiting twice as long after each one
+    #[arg(long, env = "CONNECT_TRIES", default_value_t = 8)]
+    connect_tries: u32,
+    /// Directory where results are saved. They are only printed if unset
+    #[arg(long, env = "OUTPUT_DIR")]
+    output_dir: Option<PathBuf>,
+    /// json, json_per_query or csv
+    #[arg(long, env = "OUTPUT_FORMAT", default_value = "json")]
+    output_format: OutputFormat,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Uploads both files and waits for the results
+    Submit(SubmitArgs),
+    /// Prints the status of a job
+    Status { job_id: u64 },
+    /// Fetches the results of a finished job
+    Fetch { job_id: u64 },
+    /// Cancels a running job
+    Cancel { job_id: u64 },
+}
+
+#[derive(Args)]
+struct SubmitArgs {
+    #[arg(long, env = "POSTS_FILE")]
+    posts: PathBuf,
+    #[arg(long, env = "COMMENTS_FILE")]
+    comments: PathBuf,
+    #[arg(long, env = "CHUNK_SIZE", default_value_t = 1000)]
+    chunk_size: u32,
+    /// Comma separated queries to run: score_mean, best_meme, college_posts
+    #[arg(long, env = "QUERIES", value_delimiter = ',', default_value = "score_mean,best_meme,college_posts")]
+    queries: Vec<Query>,
+    /// Exit once the job is submitted instead of waiting for its results
+    #[arg(long)]
+    detach: bool,
+    /// Gzip the files while uploading them
+    #[arg(long)]
+    gzip: bool,
+    /// Format of both files: auto, csv or jsonl. Gzipped files are detected either way
+    #[arg(long, env = "INPUT_FORMAT", default_value = "auto")]
+    format: InputFormat,
+    /// How to group the results: none or subreddit
+    #[arg(long, env = "GROUP_BY", default_value = "none")]
+    group_by: GroupBy,
+}
+
+impl SubmitArgs {
+    fn compression(&self) -> Compression {
+        if self.gzip { Compression::Gzip } else { Compression::None }
+    }
+
+    fn encoding(&self) -> UploadEncoding {
+        UploadEncoding { compression: self.compression(), format: self.format }
+    }
+}
+
+/// Failures reported by the server, carried inside `io::Error` so they map to exit codes
+#[derive(Debug)]
+enum ClientError {
+    Server { code: ErrorCode, message: String },
+    JobFailed { job_id: u64, reason: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Server { code, message } => write!(f, "Server error {}: {}", code, message),
+            ClientError::JobFailed { job_id, reason } => write!(f, "Job {} failed: {}", job_id, reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Command::Submit(args) => submit(&cli, args),
+        Command::Status { job_id } => status(&cli, *job_id),
+        Command::Fetch { job_id } => fetch(&cli, *job_id),
+        Command::Cancel { job_id } => cancel(&cli, *job_id),
+    };
+    match result {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            println!("Client failed: {}", e);
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+fn exit_code(e: &Error) -> u8 {
+    match e.get_ref().and_then(|inner| inner.downcast_ref::<ClientError>()) {
+        // Same as a job found cancelled by its status
+        Some(ClientError::Server { code: ErrorCode::JobCancelled, .. }) => EXIT_JOB_FAILED,
+        Some(ClientError::Server { code, .. }) => EXIT_SERVER_ERROR_BASE + u16::from(*code) as u8,
+        Some(ClientError::JobFailed { .. }) => EXIT_JOB_FAILED,
+        None => EXIT_FAILURE,
+    }
+}
+
+fn submit(cli: &Cli, args: &SubmitArgs) -> io::Result<u8> {
+    let mut connection = connect_with_retries(cli)?;
+    protocol::write_frame(&mut connection, &Frame::Start { queries: args.queries.clone(), encoding: args.encoding(), group_by: args.group_by })?;
+    let upload_id = wait_until_ready(&mut connection)?;
+    println!("Uploading job {}", upload_id);
+    let mut position = (FileKind::Posts, 0);
+    while let Err(e) = upload(&mut connection, args, position) {
+        if !is_disconnection(&e) {
+            return Err(e);
+        }
+        println!("Upload interrupted: {}", e);
+        (connection, position) = resume(&cli.server, upload_id)?;
+    }
     println!("Client finished sending everything");
+    if args.detach {
+        wait_until_submitted(&mut connection)?;
+        let _ = connection.shutdown(Both);
+        println!("Job {} submitted. Use the status and fetch commands to follow it", upload_id);
+        return Ok(EXIT_SUCCESS);
+    }
     println!("Client waiting for results");
-    receive_results(&mut connection);
-    let _ = connection.shutdown(Both);
-    println!("Client exiting");
-}
-
-pub fn send_file(connection: &mut TcpStream, path: &str, chunk_size: u32) -> io::Result<()> {
-    let file = std::fs::File::open(path)?;
-    let file_size = file.metadata()?.len();
-    println!("file_size is {:?}", file_size);
-    let mut buf_reader = BufReader::new(file);
-    connection.write_all(&file_size.to_be_bytes())?;
-    let mut sent = 0;
+    let results = match receive_results(&mut connection, &args.queries) {
+        Err(e) if is_disconnection(&e) => {
+            println!("Lost connection while waiting for results: {}", e);
+            poll_results(&cli.server, upload_id, &args.queries)
+        }
+        result => {
+            let _ = connection.shutdown(Both);
+            result
+        }
+    }?;
+    finish(cli, upload_id, &results)
+}
+
+fn status(cli: &Cli, job_id: u64) -> io::Result<u8> {
+    let mut connection = connect_with_retries(cli)?;
+    protocol::write_frame(&mut connection, &Frame::Status { job_id })?;
+    match protocol::read_frame(&mut connection)? {
+        Frame::JobStatus { status, .. } => {
+            println!("Job {} is {:?}", job_id, status);
+            match status {
+                JobStatus::Failed { .. } | JobStatus::Cancelled => Ok(EXIT_JOB_FAILED),
+                _ => Ok(EXIT_SUCCESS),
+            }
+        }
+        frame => Err(unexpected_frame(frame)),
+    }
+}
+
+fn fetch(cli: &Cli, job_id: u64) -> io::Result<u8> {
+    let mut connection = connect_with_retries(cli)?;
+    protocol::write_frame(&mut connection, &Frame::Fetch { job_id })?;
+    let results = receive_results(&mut connection, &Query::ALL)?;
+    finish(cli, job_id, &results)
+}
+
+fn cancel(cli: &Cli, job_id: u64) -> io::Result<u8> {
+    let mut connection = connect_with_retries(cli)?;
+    protocol::write_frame(&mut connection, &Frame::Cancel { job_id })?;
+    match protocol::read_frame(&mut connection)? {
+        Frame::JobStatus { status, .. } => {
+            println!("Job {} is {:?}", job_id, status);
+            Ok(EXIT_SUCCESS)
+        }
+        frame => Err(unexpected_frame(frame)),
+    }
+}
+
+/// Saves the results if an output directory is set and returns the exit code they deserve
+fn finish(cli: &Cli, job_id: u64, results: &JobResults) -> io::Result<u8> {
+    if let Some(output_dir) = &cli.output_dir {
+        std::fs::create_dir_all(output_dir)?;
+        let base_path = output_dir.join(format!("job-{}", job_id));
+        let paths = output::write_results(&base_path.to_string_lossy(), results, cli.output_format)?;
+        println!("Results saved to {:?}", paths);
+    }
+    if results.missing.is_empty() {
+        Ok(EXIT_SUCCESS)
+    } else {
+        Ok(EXIT_PARTIAL_RESULTS)
+    }
+}
+
+/// Polls the job status until it finishes, then fetches its results
+fn poll_results(server_address: &str, job_id: u64, queries: &[Query]) -> io::Result<JobResults> {
     loop {
-        let mut chunk = Vec::with_capacity(chunk_size as usize);
-        let read_size = buf_reader.by_ref().take(chunk_size as u64).read_to_end(&mut chunk)?;
-        connection.write_all(&chunk)?;
-        sent += read_size as u64;
-        if sent >= file_size { break; }
+        thread::sleep(Duration::from_secs(SEC_BETWEEN_POLLS));
+        let status = connect(server_address).and_then(|mut connection| {
+            protocol::write_frame(&mut connection, &Frame::Status { job_id })?;
+            protocol::read_frame(&mut connection)
+        });
+        match status {
+            Ok(Frame::JobStatus { status: JobStatus::Finished, .. }) => break,
+            Ok(Frame::JobStatus { status: JobStatus::Failed { reason }, .. }) => {
+                return Err(Error::other(ClientError::JobFailed { job_id, reason }));
+            }
+            Ok(Frame::JobStatus { status: JobStatus::Cancelled, .. }) => {
+                return Err(Error::other(ClientError::JobFailed { job_id, reason: "cancelled".to_string() }));
+            }
+            Ok(Frame::JobStatus { status, .. }) => println!("Job {} is {:?}", job_id, status),
+            Ok(frame) => return Err(unexpected_frame(frame)),
+            Err(e) if is_disconnection(&e) => println!("Failed to poll job {}: {}. Will retry", job_id, e),
+            Err(e) => return Err(e),
+        }
+    }
+    let mut connection = connect(server_address)?;
+    protocol::write_frame(&mut connection, &Frame::Fetch { job_id })?;
+    receive_results(&mut connection, queries)
+}
+
+/// Connects to the server, backing off exponentially while it isn't reachable
+fn connect_with_retries(cli: &Cli) -> io::Result<TcpStream> {
+    let mut backoff = FIRST_CONNECT_BACKOFF;
+    let mut tries = cli.connect_tries.max(1);
+    loop {
+        match connect(&cli.server) {
+            Err(e) if is_disconnection(&e) && tries > 1 => {
+                println!("Failed to connect to {}: {}. Retrying in {:?}", cli.server, e, backoff);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                tries -= 1;
+            }
+            result => return result,
+        }
     }
-    Ok(())
 }
 
-pub fn receive_results(connection:&mut TcpStream) {
-    let best_meme = read_string(connection);
-    println!("Best meme received: {:?}", best_meme);
-    if best_meme == "Server not available" {
-        return
+fn connect(server_address: &str) -> io::Result<TcpStream> {
+    let mut connection = TcpStream::connect(server_address)?;
+    let server_version = protocol::handshake(&mut connection)?;
+    if server_version != PROTOCOL_VERSION {
+        return Err(Error::new(ErrorKind::Unsupported, format!("Server speaks protocol version {}, expected {}", server_version, PROTOCOL_VERSION)));
     }
-    let score_mean = read_string(connection);
-    println!("Score mean received: {:?}", score_mean);
-    if best_meme == "Server not available" {
-        return
+    Ok(connection)
+}
+
+/// Waits for a free job slot in the server. Returns the upload id
+fn wait_until_ready(connection: &mut TcpStream) -> io::Result<u64> {
+    loop {
+        match protocol::read_frame(connection)? {
+            Frame::Ready { upload_id } => return Ok(upload_id),
+            Frame::Progress(Progress::Queued { position }) => {
+                println!("Waiting for the server, queue position: {}", position);
+            }
+            frame => return Err(unexpected_frame(frame)),
+        }
     }
-    let mut college_posts = vec![];
+}
+
+/// Waits until the server confirms the upload was submitted as a job
+fn wait_until_submitted(connection: &mut TcpStream) -> io::Result<()> {
     loop {
-        let msg = read_string(connection);
-        if msg == "FINISHED" || msg == "Server not available" || msg == "" {
-            println!("Received end: {}", msg);
-            break;
+        match protocol::read_frame(connection)? {
+            Frame::Submitted { .. } => return Ok(()),
+            Frame::Progress(_) => {}
+            frame => return Err(unexpected_frame(frame)),
         }
-        college_posts.push(msg);
     }
-    println!("College posts received: {:?}", college_posts.len());
 }
 
-pub fn read_string(connection:&mut TcpStream) -> String {
-    let best_meme_size = read_msg_size(connection).expect("Could not receive msg len");
-    let best_meme_vec = read_chunk(connection, best_meme_size).expect("Failed to read best meme size");
-    str::from_utf8(&best_meme_vec).unwrap().to_string()
+/// Uploads both files starting from `position`
+fn upload(connection: &mut TcpStream, args: &SubmitArgs, position: (FileKind, u64)) -> io::Result<()> {
+    let (file, offset) = position;
+    if file == FileKind::Posts {
+        send_file(connection, &args.posts, FileKind::Posts, offset, args.chunk_size, args.compression())?;
+    }
+    let comments_offset = if file == FileKind::Comments { offset } else { 0 };
+    send_file(connection, &args.comments, FileKind::Comments, comments_offset, args.chunk_size, args.compression())
 }
 
-pub fn read_msg_size(stream: &mut TcpStream) -> io::Result<u64> {
-    let mut file_size_buff = [0u8; 8];
-    let mut n_received = 0;
+/// Reconnects to an interrupted upload. Returns the new connection and where to continue from
+fn resume(server_address: &str, upload_id: u64) -> io::Result<(TcpStream, (FileKind, u64))> {
+    let mut tries = MAX_RESUME_TRIES;
     loop {
-        let n_bytes = stream.read(&mut file_size_buff[n_received..])?;
-        n_received += n_bytes;
-        if n_received == file_size_buff.len() {
-            break;
+        thread::sleep(Duration::from_secs(SEC_BETWEEN_RESUME_TRIES));
+        match try_resume(server_address, upload_id) {
+            Ok(resumed) => return Ok(resumed),
+            Err(e) if is_disconnection(&e) && tries > 1 => {
+                println!("Failed to resume upload {}: {}. Will retry", upload_id, e);
+                tries -= 1;
+            }
+            Err(e) => return Err(e),
         }
-        if n_bytes == 0 {
-            break;
+    }
+}
+
+fn try_resume(server_address: &str, upload_id: u64) -> io::Result<(TcpStream, (FileKind, u64))> {
+    let mut connection = connect(server_address)?;
+    protocol::write_frame(&mut connection, &Frame::Resume { upload_id })?;
+    match protocol::read_frame(&mut connection)? {
+        Frame::Resumed { file, offset } => {
+            println!("Resuming {:?} upload from byte {}", file, offset);
+            Ok((connection, (file, offset)))
         }
+        frame => Err(unexpected_frame(frame)),
     }
-    return Ok(u64::from_be_bytes(file_size_buff));
 }
 
-pub fn read_chunk(stream: &mut TcpStream, n: u64) -> io::Result<Vec<u8>> {
-    let mut received = vec![0; n as usize];
-    let mut n_received = 0;
+fn is_disconnection(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::TimedOut
+    )
+}
+
+/// Sends a file from `offset`, keeping at most `ACK_WINDOW` chunks unacknowledged. Offsets of
+/// compressed files count compressed bytes, so resuming one compresses it again up to `offset`
+pub fn send_file(connection: &mut TcpStream, path: &Path, file: FileKind, offset: u64, chunk_size: u32, compression: Compression) -> io::Result<()> {
+    let mut file_handle = std::fs::File::open(path)?;
+    println!("file_size is {:?}", file_handle.metadata()?.len());
+    let mut buf_reader: Box<dyn Read> = match compression {
+        Compression::None => {
+            file_handle.seek(SeekFrom::Start(offset))?;
+            Box::new(BufReader::new(file_handle))
+        }
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(BufReader::new(file_handle), flate2::Compression::default());
+            io::copy(&mut encoder.by_ref().take(offset), &mut io::sink())?;
+            Box::new(encoder)
+        }
+    };
+    let mut offset = offset;
+    let mut unacknowledged = 0;
+    loop {
+        let mut data = Vec::with_capacity(chunk_size as usize);
+        buf_reader.by_ref().take(chunk_size as u64).read_to_end(&mut data)?;
+        let finished = data.is_empty();
+        let chunk_size = data.len() as u64;
+        protocol::write_frame(connection, &Frame::Upload { file, offset, data })?;
+        offset += chunk_size;
+        unacknowledged += 1;
+        while unacknowledged >= ACK_WINDOW || (finished && unacknowledged > 0) {
+            match protocol::read_frame(connection)? {
+                Frame::UploadAck { .. } => unacknowledged -= 1,
+                frame => return Err(unexpected_frame(frame)),
+            }
+        }
+        if finished { break; }
+    }
+    Ok(())
+}
+
+pub fn receive_results(connection: &mut TcpStream, queries: &[Query]) -> io::Result<JobResults> {
+    let mut results = JobResults::default();
     loop {
-        let n_bytes = stream.read(&mut received[n_received..])?;
-        n_received += n_bytes;
-        if n_received == received.len() {
-            break;
-        }
-        if n_bytes == 0 {
-            break;
-        }
-    }
-    return Ok(received);
-}
-
-#[derive(Clone, Envconfig)]
-pub struct ClientConfig {
-    /// Configuration file with the run commands for every service
-    #[envconfig(from = "CHUNK_SIZE", default = "1000")]
-    pub chunk_size: u32,
-    #[envconfig(from = "SERVER_ADDRESS", default = "server:6789")]
-    pub server_address: String,
-    #[envconfig(from = "POSTS_FILE", default = "")]
-    pub posts_path: String,
-    #[envconfig(from = "COMMENTS_FILE", default = "")]
-    pub comments_path: String,
-}
\ No newline at end of file
+        match protocol::read_frame(connection)? {
+            Frame::Result(QueryResult::BestMemes(group, best_memes)) => {
+                for (rank, meme) in best_memes.iter().enumerate() {
+                    println!("{}Best meme #{}: {} ({}), sentiment {}", group_label(&group), rank + 1, meme.url, meme.id, meme.sentiment);
+                }
+                results.group_mut(&group).best_memes = Some(best_memes);
+            }
+            Frame::Result(QueryResult::ScoreStats(group, score_stats)) => {
+                println!(
+                    "{}Score stats received: mean {}, stddev {}, median {}, p90 {}, p99 {} of {} posts",
+                    group_label(&group), score_stats.mean, score_stats.stddev, score_stats.median, score_stats.p90, score_stats.p99, score_stats.count
+                );
+                results.group_mut(&group).score_stats = Some(score_stats);
+            }
+            Frame::Result(QueryResult::CollegePost(group, college_post)) => {
+                results.group_mut(&group).college_posts.get_or_insert_with(Vec::new).push(college_post);
+            }
+            Frame::Result(QueryResult::CollegeKeywordHits(hits)) => {
+                println!("College keyword hits: {:?}", hits);
+                results.college_keyword_hits = hits;
+            }
+            Frame::Progress(Progress::Stage { stage, progress }) => {
+                let finished = if progress.finished { ", finished" } else { "" };
+                println!("{}: {} rows in {} batches{}", stage, progress.rows, progress.batches, finished);
+            }
+            Frame::Progress(progress) => {
+                println!("Progress: {:?}", progress);
+            }
+            Frame::Submitted { job_id } => {
+                println!("Job {} submitted", job_id);
+            }
+            Frame::Rejected { rows } => {
+                for rejected in &rows {
+                    println!("Skipped {} {:?} rows: {}", rejected.rows, rejected.file, rejected.reason);
+                }
+                results.rejected = rows;
+            }
+            Frame::Partial { missing } => {
+                println!("Job deadline expired, results are partial. Missing queries: {:?}", missing);
+                results.missing = missing;
+            }
+            Frame::Finished => {
+                println!("Received end");
+                break;
+            }
+            frame => return Err(unexpected_frame(frame)),
+        }
+    }
+    if results.group_by == GroupBy::None && queries.contains(&Query::CollegePosts) {
+        results.global.college_posts.get_or_insert_with(Vec::new);
+    }
+    for (group, group_results) in results.groups() {
+        println!("{}College posts received: {:?}", group_label(group), group_results.college_posts.as_ref().map(Vec::len));
+    }
+    Ok(results)
+}
+
+/// Prefix of the lines printed about a group of results
+fn group_label(group: &str) -> String {
+    if group.is_empty() { String::new() } else { format!("[{}] ", group) }
+}
+
+fn unexpected_frame(frame: Frame) -> Error {
+    match frame {
+        Frame::Error { code, message } => Error::other(ClientError::Server { code, message }),
+        frame => Error::new(ErrorKind::InvalidData, format!("Unexpected frame: {:?}", frame)),
+    }
+}
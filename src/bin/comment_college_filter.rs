@@ -1,10 +1,12 @@
 use amiquip::Result;
-use log::warn;
+use log::{info, warn};
+use tp2::keywords::{KeywordMatcher, DEFAULT_COLLEGE_KEYWORDS};
 use tp2::messages::Message;
 use tp2::middleware::message_processor::MessageProcessor;
 use tp2::middleware::service::{init, RabbitService};
-use tp2::{Config, COMMENT_COLLEGE_QUEUE_NAME, POST_ID_COLLEGE_QUEUE_NAME};
+use tp2::{Config, COMMENT_COLLEGE_QUEUE_NAME, POST_URL_AVERAGE_QUEUE_NAME};
 use tp2::health_checker::health_answerer::HealthAnswerer;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
@@ -30,22 +32,39 @@ fn main() -> Result<()> {
 }
 
 fn run_service(config: Config) -> Result<()> {
-    let mut processor = CommentCollegeFilter;
-    let mut service = RabbitService::new(config, &mut processor);
+    let list: String =
+        envconfig::load_var_with_default("COLLEGE_KEYWORDS", None, DEFAULT_COLLEGE_KEYWORDS).unwrap();
+    let file: String = envconfig::load_var_with_default("COLLEGE_KEYWORDS_FILE", None, "").unwrap();
+    let keywords = KeywordMatcher::load(&list, &file).expect("Invalid college keywords");
+    info!("Matching college keywords {:?}", keywords.keywords());
+    let processor = CommentCollegeFilter {
+        keywords: Arc::new(keywords),
+        hits: BTreeMap::new(),
+    };
+    let mut service = RabbitService::new(config, processor);
     service.run(
         COMMENT_COLLEGE_QUEUE_NAME,
-        Some(POST_ID_COLLEGE_QUEUE_NAME.to_string()),
+        Some(POST_URL_AVERAGE_QUEUE_NAME.to_string()),
     )
 }
 
-struct CommentCollegeFilter;
+#[derive(Clone)]
+struct CommentCollegeFilter {
+    keywords: Arc<KeywordMatcher>,
+    /// Comments of the session that matched each keyword
+    hits: BTreeMap<String, u64>,
+}
 
 impl MessageProcessor for CommentCollegeFilter {
-    type State = ();
+    type State = BTreeMap<String, u64>;
     fn process_message(&mut self, message: Message) -> Option<Message> {
         match message {
             Message::FullComment(comment) => {
-                if comment.is_college_related() {
+                let matched = comment.college_keywords(&self.keywords);
+                for keyword in &matched {
+                    *self.hits.entry(keyword.to_string()).or_default() += 1;
+                }
+                if !matched.is_empty() {
                     let post_id = comment.parse_post_id()?;
                     return Some(Message::PostId(post_id));
                 }
@@ -56,4 +75,17 @@ impl MessageProcessor for CommentCollegeFilter {
         }
         None
     }
+
+    fn on_stream_finished(&self) -> Vec<Message> {
+        info!("College keyword hits: {:?}", self.hits);
+        vec![Message::CollegeKeywordHits(self.hits.clone())]
+    }
+
+    fn get_state(&self) -> Option<Self::State> {
+        Some(self.hits.clone())
+    }
+
+    fn set_state(&mut self, state: Self::State) {
+        self.hits = state;
+    }
 }
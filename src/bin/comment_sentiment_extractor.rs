@@ -30,14 +30,15 @@ fn main() -> Result<()> {
 }
 
 fn run_service(config: Config) -> Result<()> {
-    let mut processor = CommentSentimentExtractor;
-    let mut service = RabbitService::new(config, &mut processor);
+    let processor = CommentSentimentExtractor;
+    let mut service = RabbitService::new(config, processor);
     service.run(
         COMMENT_SENTIMENT_QUEUE_NAME,
         Some(POST_ID_SENTIMENT_QUEUE_NAME.to_string()),
     )
 }
 
+#[derive(Clone)]
 struct CommentSentimentExtractor;
 
 impl MessageProcessor for CommentSentimentExtractor {
@@ -46,8 +47,7 @@ impl MessageProcessor for CommentSentimentExtractor {
         match message {
             Message::FullComment(comment) => {
                 let post_id = comment.parse_post_id()?;
-                let sentiment = str::parse::<f32>(&comment.sentiment).ok()?;
-                Some(Message::PostIdSentiment(post_id, sentiment))
+                Some(Message::PostIdSentiment(comment.group, post_id, comment.sentiment?))
             }
             _ => {
                 warn!("Invalid message arrived");
@@ -4,8 +4,11 @@ use tp2::messages::Message;
 use tp2::middleware::message_processor::MessageProcessor;
 use tp2::middleware::service::{init, RabbitService};
 use tp2::middleware::RabbitExchange;
-use tp2::{Config, POST_SCORE_AVERAGE_QUEUE_NAME, POST_SCORE_MEAN_QUEUE_NAME, RESULTS_QUEUE_NAME};
+use tp2::protocol::ScoreSummary;
+use tp2::stats::ScoreStats;
+use tp2::{Config, POST_COLLEGE_QUEUE_NAME, POST_SCORE_MEAN_QUEUE_NAME, RESULTS_QUEUE_NAME};
 use tp2::health_checker::health_answerer::HealthAnswerer;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
@@ -31,24 +34,24 @@ fn main() -> Result<()> {
 }
 
 fn run_service(config: Config) -> Result<()> {
-    let mut processor = MeanCalculator::default();
-    let mut service = RabbitService::new(config, &mut processor);
+    let processor = MeanCalculator::default();
+    let mut service = RabbitService::new(config, processor);
     service.run(POST_SCORE_MEAN_QUEUE_NAME, None)
 }
 
-#[derive(Default)]
+/// Merges the partial score stats of the score extractor replicas into the stats of each group.
+/// Its `PRODUCERS` must be the number of replicas, as each sends its own EOS
+#[derive(Clone, Default)]
 struct MeanCalculator {
-    score_count: u32,
-    score_sum: u32,
+    stats: BTreeMap<String, ScoreStats>,
 }
 
 impl MessageProcessor for MeanCalculator {
-    type State = (u32, u32);
+    type State = BTreeMap<String, ScoreStats>;
     fn process_message(&mut self, message: Message) -> Option<Message> {
         match message {
-            Message::PostScore(score) => {
-                self.score_count += 1;
-                self.score_sum += score;
+            Message::PostScores(group, partial) => {
+                self.stats.entry(group).or_default().merge(&partial);
             }
             _ => {
                 warn!("Invalid message arrived");
@@ -57,10 +60,15 @@ impl MessageProcessor for MeanCalculator {
         None
     }
 
-    fn on_stream_finished(&self) -> Option<Message> {
-        let mean = self.score_sum as f32 / self.score_count as f32;
-        info!("End of stream received, sending mean: {}", mean);
-        Some(Message::PostScoreMean(mean))
+    fn on_stream_finished(&self) -> Vec<Message> {
+        let mut summaries: BTreeMap<String, ScoreSummary> =
+            self.stats.iter().map(|(group, stats)| (group.clone(), stats.summary())).collect();
+        if summaries.is_empty() {
+            // Jobs without posts still get empty stats
+            summaries.insert(String::new(), ScoreSummary::default());
+        }
+        info!("End of stream received, sending score stats of {} groups", summaries.len());
+        vec![Message::PostScoreStats(summaries)]
     }
 
     fn send_process_output<E: RabbitExchange>(
@@ -69,16 +77,17 @@ impl MessageProcessor for MeanCalculator {
         message: Message,
     ) -> Result<()> {
         exchange.send_with_key(&message, RESULTS_QUEUE_NAME)?;
-        exchange.send_with_key(&Message::Confirmed, RESULTS_QUEUE_NAME)?;
-        exchange.send_with_key(&message, POST_SCORE_AVERAGE_QUEUE_NAME)?;
-        exchange.send_with_key(&Message::Confirmed, POST_SCORE_AVERAGE_QUEUE_NAME)
+        exchange.send_with_key(&message.confirm(), RESULTS_QUEUE_NAME)?;
+        // The average filter reads the stats from the same queue as the posts it filters
+        exchange.send_with_key(&message, POST_COLLEGE_QUEUE_NAME)?;
+        exchange.send_with_key(&message.confirm(), POST_COLLEGE_QUEUE_NAME)
     }
 
     fn get_state(&self) -> Option<Self::State> {
-        Some((self.score_count, self.score_sum))
+        Some(self.stats.clone())
     }
 
     fn set_state(&mut self, state: Self::State) {
-        (self.score_count, self.score_sum) = state;
+        self.stats = state;
     }
 }
@@ -1,13 +1,12 @@
-use std::ops::Add;
 use amiquip::Result;
 use log::{info, warn};
 use tp2::messages::Message;
 use tp2::middleware::message_processor::MessageProcessor;
 use tp2::middleware::service::{init, RabbitService};
-use tp2::{
-    Config, POST_COLLEGE_QUEUE_NAME, POST_SCORE_AVERAGE_QUEUE_NAME, POST_URL_AVERAGE_QUEUE_NAME,
-};
+use tp2::stats::Statistic;
+use tp2::{Config, POST_COLLEGE_QUEUE_NAME, POST_URL_AVERAGE_QUEUE_NAME};
 use tp2::health_checker::health_answerer::HealthAnswerer;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
@@ -33,73 +32,87 @@ fn main() -> Result<()> {
 }
 
 fn run_service(config: Config) -> Result<()> {
-    info!("Getting score average");
-    let mut processor = PostAverageConsumer::default();
-    let mut consumer = RabbitService::new_subservice(config.clone(), &mut processor);
-    consumer.run_once(POST_SCORE_AVERAGE_QUEUE_NAME, None)?;
-    let consumer_transaction_log_path = config.transaction_log_path.clone().add(".subservice");
-    if let Some(score_average) = processor.score_average {
-        info!("Filtering above average");
-        let mut processor = PostAverageFilter { score_average };
-        // FIX: Seems that closing and opening a connection so fast crashes the app, putting a sleep
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        let mut service = RabbitService::new(config, &mut processor);
-        service.run(
-            POST_COLLEGE_QUEUE_NAME,
-            Some(POST_URL_AVERAGE_QUEUE_NAME.to_string()),
-        )?;
-    } else {
-        warn!("Couldn't pop score average");
-    }
-    std::fs::remove_file(&consumer_transaction_log_path);
-    Ok(())
+    let threshold: Statistic = envconfig::load_var_with_default("SCORE_THRESHOLD", None, "mean").unwrap();
+    info!("Filtering posts scored above the {:?}", threshold);
+    let processor = PostAverageFilter {
+        threshold,
+        ..Default::default()
+    };
+    let mut service = RabbitService::new(config, processor);
+    service.run(
+        POST_COLLEGE_QUEUE_NAME,
+        Some(POST_URL_AVERAGE_QUEUE_NAME.to_string()),
+    )
 }
 
+/// Posts and the score stats arrive through the same queue. Posts received before the stats
+/// are kept until they arrive. Each post is compared to the stats of its own group.
+#[derive(Clone, Default)]
 struct PostAverageFilter {
-    score_average: f32,
+    /// Statistic of the scores a post must be above
+    threshold: Statistic,
+    score_thresholds: Option<HashMap<String, f64>>,
+    pending_posts: Vec<(String, String, String, u32)>,
+}
+
+impl PostAverageFilter {
+    fn filter(&self, group: String, id: String, url: String, score: u32, score_thresholds: &HashMap<String, f64>) -> Option<Message> {
+        if score as f64 > *score_thresholds.get(&group)? {
+            return Some(Message::PostUrl(group, id, url));
+        }
+        None
+    }
 }
 
 impl MessageProcessor for PostAverageFilter {
-    type State = ();
+    type State = (Option<HashMap<String, f64>>, Vec<(String, String, String, u32)>);
     fn process_message(&mut self, message: Message) -> Option<Message> {
         match message {
             Message::FullPost(post) => {
-                if post.score as f32 > self.score_average && post.url.starts_with("https") {
-                    return Some(Message::PostUrl(post.id, post.url));
+                if !post.url.starts_with("https") {
+                    return None;
+                }
+                match &self.score_thresholds {
+                    Some(score_thresholds) => {
+                        return self.filter(post.group, post.id, post.url, post.score, score_thresholds);
+                    }
+                    None => self.pending_posts.push((post.group, post.id, post.url, post.score)),
                 }
             }
+            Message::PostScoreStats(stats) => {
+                let score_thresholds = stats
+                    .iter()
+                    .map(|(group, stats)| (group.clone(), self.threshold.of(stats)))
+                    .collect();
+                self.score_thresholds = Some(score_thresholds);
+            }
             _ => {
                 warn!("Invalid message arrived");
             }
         }
         None
     }
-}
 
-#[derive(Default)]
-struct PostAverageConsumer {
-    score_average: Option<f32>,
-}
-
-impl MessageProcessor for PostAverageConsumer {
-    type State = f32;
-    fn process_message(&mut self, message: Message) -> Option<Message> {
-        match message {
-            Message::PostScoreMean(mean) => {
-                self.score_average = Some(mean);
+    fn on_stream_finished(&self) -> Vec<Message> {
+        let score_thresholds = match &self.score_thresholds {
+            Some(score_thresholds) => score_thresholds,
+            None => {
+                warn!("Stream finished without score stats");
+                return vec![];
             }
-            _ => {
-                warn!("Invalid message arrived");
-            }
-        }
-        None
+        };
+        self.pending_posts
+            .iter()
+            .cloned()
+            .flat_map(|(group, id, url, score)| self.filter(group, id, url, score, score_thresholds))
+            .collect()
     }
 
     fn get_state(&self) -> Option<Self::State> {
-        self.score_average
+        Some((self.score_thresholds.clone(), self.pending_posts.clone()))
     }
 
     fn set_state(&mut self, state: Self::State) {
-        self.score_average = Some(state);
+        (self.score_thresholds, self.pending_posts) = state;
     }
 }
@@ -1,11 +1,10 @@
 use amiquip::Result;
-use log::{info, warn};
-use std::collections::HashSet;
-use std::ops::Add;
+use log::warn;
+use std::collections::{HashMap, HashSet};
 use tp2::messages::Message;
 use tp2::middleware::message_processor::MessageProcessor;
 use tp2::middleware::service::{init, RabbitService};
-use tp2::{Config, POST_ID_COLLEGE_QUEUE_NAME, POST_URL_AVERAGE_QUEUE_NAME, RESULTS_QUEUE_NAME};
+use tp2::{Config, POST_URL_AVERAGE_QUEUE_NAME, RESULTS_QUEUE_NAME};
 use tp2::health_checker::health_answerer::HealthAnswerer;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -32,59 +31,40 @@ fn main() -> Result<()> {
 }
 
 fn run_service(config: Config) -> Result<()> {
-    info!("Getting college post ids");
-    let ids = get_college_posts_ids(&config)?;
-    info!("Filtering college posts");
-    let mut processor = PostCollegeFilter { ids };
-    // FIX: Seems that closing and opening a connection so fast crashes the app, putting a sleep
-    std::thread::sleep(std::time::Duration::from_secs(1));
-    let consumer_transaction_log_path = config.transaction_log_path.clone().add(".subservice");
-    let mut service = RabbitService::new(config, &mut processor);
+    let processor = PostCollegeFilter::default();
+    let mut service = RabbitService::new(config, processor);
     service.run(
         POST_URL_AVERAGE_QUEUE_NAME,
         Some(RESULTS_QUEUE_NAME.to_string()),
-    )?;
-    std::fs::remove_file(consumer_transaction_log_path);
-    Ok(())
+    )
 }
 
+/// Above average post urls and college post ids arrive through the same queue. Urls are
+/// kept until their post id shows up as college related. The keyword hits of the comments
+/// are passed on to the results.
+#[derive(Clone, Default)]
 struct PostCollegeFilter {
     ids: HashSet<String>,
+    /// Group and url of each post
+    pending_urls: HashMap<String, (String, String)>,
 }
 
 impl MessageProcessor for PostCollegeFilter {
-    type State = ();
+    type State = (HashSet<String>, HashMap<String, (String, String)>);
     fn process_message(&mut self, message: Message) -> Option<Message> {
         match message {
-            Message::PostUrl(id, url) => {
+            Message::PostUrl(group, id, url) => {
                 if self.ids.contains(&id) {
-                    return Some(Message::CollegePostUrl(url));
+                    return Some(Message::CollegePostUrl(group, url));
                 }
+                self.pending_urls.insert(id, (group, url));
             }
-            _ => {
-                warn!("Invalid message arrived");
-            }
-        }
-        None
-    }
-
-    fn on_stream_finished(&self) -> Option<Message> {
-        Some(Message::CollegePostEnded)
-    }
-}
-
-#[derive(Default)]
-struct CollegePostIdConsumer {
-    ids: HashSet<String>,
-}
-
-impl MessageProcessor for CollegePostIdConsumer {
-    type State = HashSet<String>;
-    fn process_message(&mut self, message: Message) -> Option<Message> {
-        match message {
             Message::PostId(id) => {
+                let url = self.pending_urls.remove(&id);
                 self.ids.insert(id);
+                return url.map(|(group, url)| Message::CollegePostUrl(group, url));
             }
+            hits @ Message::CollegeKeywordHits(_) => return Some(hits),
             _ => {
                 warn!("Invalid message arrived");
             }
@@ -92,19 +72,15 @@ impl MessageProcessor for CollegePostIdConsumer {
         None
     }
 
+    fn on_stream_finished(&self) -> Vec<Message> {
+        vec![Message::CollegePostEnded]
+    }
+
     fn get_state(&self) -> Option<Self::State> {
-        Some(self.ids.clone())
+        Some((self.ids.clone(), self.pending_urls.clone()))
     }
 
     fn set_state(&mut self, state: Self::State) {
-        self.ids = state;
+        (self.ids, self.pending_urls) = state;
     }
 }
-
-fn get_college_posts_ids(config: &Config) -> Result<HashSet<String>> {
-    let config = config.clone();
-    let mut processor = CollegePostIdConsumer::default();
-    let mut service = RabbitService::new_subservice(config, &mut processor);
-    service.run(POST_ID_COLLEGE_QUEUE_NAME, None)?;
-    Ok(processor.ids)
-}
@@ -1,7 +1,9 @@
 use amiquip::{ExchangeType, Result};
 use envconfig::Envconfig;
-use log::info;
-use std::net::TcpListener;
+use log::{error, info, warn};
+use std::io::{self, Read};
+use std::fs::File;
+use std::net::{TcpListener, TcpStream};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -9,19 +11,26 @@ use std::time::Duration;
 use tp2::health_checker::health_answerer::HealthAnswerer;
 use tp2::health_checker::health_answerer_handler::HealthAnswerHandler;
 use tp2::health_checker::health_base::HealthBase;
-use tp2::messages::Message;
+use tp2::messages::{Message, ProgressUpdate};
 use tp2::middleware::buf_exchange::BufExchange;
 use tp2::middleware::connection::{BinaryExchange, RabbitConnection};
+use tp2::middleware::progress::{self, ProgressPublisher, ROWS_PER_PROGRESS_EVENT};
 use tp2::middleware::RabbitExchange;
-use tp2::post::PostIterator;
+use tp2::protocol::{FileKind, GroupBy, InputFormat, Query};
+use tp2::post::{Post, PostIterator};
 use tp2::sigterm_handler::sigterm_handler::handle_sigterm;
-use tp2::{Config, POSTS_SOURCE_EXCHANGE_NAME};
+use tp2::{branches, Config, POSTS_SOURCE_EXCHANGE_NAME, RESULTS_QUEUE_NAME};
 
 fn main() -> Result<()> {
     let env_config = Config::init_from_env().unwrap();
     println!("Setting logger level: {}", env_config.logging_level);
     std::env::set_var("RUST_LOG", env_config.logging_level.clone());
     env_logger::init();
+    let input_file: String = envconfig::load_var_with_default("INPUT_FILE", None, "").unwrap();
+    if !input_file.is_empty() {
+        // Runs once without the server, so there are no signals to wait for or health checks
+        return publish_local_posts(env_config, &input_file);
+    }
     let shutdown = Arc::new(AtomicBool::new(false));
     let shutdown_handler_join = shutdown.clone();
     let sigterm_handler_join = thread::spawn(move || handle_sigterm(shutdown_handler_join));
@@ -37,45 +46,156 @@ fn main() -> Result<()> {
 }
 
 fn run_service(config: Config, shutdown: Arc<AtomicBool>) -> Result<()> {
+    let listener = TcpListener::bind("0.0.0.0:9090").expect("Could not bind listener");
+
+    listener
+        .set_nonblocking(true)
+        .expect("Could not set non blocking to true");
+
+    let mut workers = vec![];
+    for stream_result in listener.incoming() {
+        match stream_result {
+            Ok(stream) => {
+                let config = config.clone();
+                workers.push(thread::spawn(move || {
+                    if let Err(e) = publish_posts(config, stream) {
+                        error!("Failed to publish posts: {:?}", e);
+                    }
+                }));
+            }
+            Err(_) => {
+                if shutdown.load(Ordering::Relaxed) {
+                    break
+                } else {
+                    thread::sleep(Duration::from_secs(1));
+                    continue
+                }
+            }
+        }
+        workers.retain(|worker| !worker.is_finished());
+    }
+    for worker in workers {
+        worker.join().expect("Failed to join posts publisher");
+    }
+    info!("Exit");
+    Ok(())
+}
+
+/// Publishes the posts of a single client session
+fn publish_posts(config: Config, mut stream: TcpStream) -> Result<()> {
+    stream
+        .set_nonblocking(false)
+        .expect("Could not set blocking to true");
+    // Session id followed by the queries it asked for, the format of its file and how its
+    // results are grouped
+    let mut header = [0u8; 11];
+    if let Err(e) = stream.read_exact(&mut header) {
+        error!("Failed to read session header: {:?}", e);
+        return Ok(());
+    }
+    let session_id = u64::from_be_bytes(header[..8].try_into().unwrap());
+    let format = InputFormat::from_byte(header[9]).unwrap_or_default();
+    let group_by = GroupBy::from_byte(header[10]).unwrap_or_default();
+    publish_session(config, session_id, &Query::from_mask(header[8]), format, group_by, stream)
+}
+
+/// Publishes a local posts file, or stdin if `path` is "-", as a session of every query,
+/// without going through the server
+fn publish_local_posts(config: Config, path: &str) -> Result<()> {
+    let session_id: u64 = envconfig::load_var_with_default("SESSION_ID", None, "0").unwrap();
+    let format: InputFormat = envconfig::load_var_with_default("INPUT_FORMAT", None, "auto").unwrap();
+    let group_by: GroupBy = envconfig::load_var_with_default("GROUP_BY", None, "none").unwrap();
+    let source: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        match File::open(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                error!("Can't open {}: {:?}", path, e);
+                return Ok(());
+            }
+        }
+    };
+    info!("Publishing {} as session {}", path, session_id);
+    publish_session(config, session_id, &Query::ALL, format, group_by, source)
+}
+
+fn publish_session<R: Read>(
+    config: Config,
+    session_id: u64,
+    queries: &[Query],
+    format: InputFormat,
+    group_by: GroupBy,
+    mut source: R,
+) -> Result<()> {
+    // Posts are spread among the score extractor replicas
+    let score_extractors: usize = envconfig::load_var_with_default("SCORE_EXTRACTORS", None, "1").unwrap();
+    let data_keys = branches::routing_keys(FileKind::Posts, queries, score_extractors);
+    let needed = !data_keys.is_empty();
+    if !needed {
+        info!("Session {} needs no posts, discarding them", session_id);
+        let _ = io::copy(&mut source, &mut io::sink());
+    }
     let connection = RabbitConnection::new(&config)?;
     {
         let consumers = str::parse::<usize>(&config.consumers).unwrap();
         let exchange =
-            connection.get_named_exchange(POSTS_SOURCE_EXCHANGE_NAME, ExchangeType::Fanout)?;
+            connection.get_named_exchange(POSTS_SOURCE_EXCHANGE_NAME, ExchangeType::Topic)?;
         let bin_exchange = BinaryExchange::new(exchange, None, 1, consumers);
-        let mut exchange = BufExchange::new(bin_exchange);
-        let listener = TcpListener::bind("0.0.0.0:9090").expect("Could not bind listener");
-        
-        listener
-            .set_nonblocking(true)
-            .expect("Could not set non blocking to true");
+        let mut exchange = BufExchange::new(bin_exchange, session_id).with_keys(data_keys);
+        let progress = ProgressPublisher::new(&connection, &progress::stage_name())?;
 
-        for stream_result in listener.incoming() {
-            match stream_result {
-                Ok(stream) => {
-
-                    let posts = PostIterator::from_stream(stream);
-                    info!("Iterating posts");
-                    let published = posts
-                        .map(Message::FullPost)
-                        .flat_map(|message| exchange.send(&message))
-                        .count();
-
-                    exchange.end_of_stream()?;
-
-                    info!("Published {} posts", published);
-                    info!("Exit");
-                }
-                Err(_) => {
-                    if shutdown.load(Ordering::Relaxed) {
-                        break
-                    } else {
-                        thread::sleep(Duration::from_secs(1));
-                        continue
-                    }
-                }
+        // The session still gets its EOS if the header can't be read
+        let mut posts = if needed {
+            PostIterator::from_reader(FileKind::Posts, source, format, &config.column_aliases, Post::parse)
+                .map_err(|e| error!("Can't read the posts of session {}: {}", session_id, e))
+                .ok()
+        } else {
+            None
+        };
+        let rejects_path: String = envconfig::load_var_with_default("REJECTS_PATH", None, "").unwrap();
+        if let Some(posts) = posts.as_mut().filter(|_| !rejects_path.is_empty()) {
+            let path = format!("{}.{}.posts.csv", rejects_path, session_id);
+            if let Err(e) = posts.save_rejects_to(&path) {
+                error!("Can't save rejected posts to {}: {:?}", path, e);
             }
         }
+        info!("Iterating posts of session {}", session_id);
+        let published = posts
+            .iter_mut()
+            .flatten()
+            .map(|mut post| {
+                post.set_group(group_by);
+                Message::FullPost(post)
+            })
+            .flat_map(|message| exchange.send(&message))
+            .enumerate()
+            .inspect(|(i, _)| {
+                if (*i as u64 + 1).is_multiple_of(ROWS_PER_PROGRESS_EVENT) {
+                    progress.publish(session_id, ProgressUpdate::RowsIngested(ROWS_PER_PROGRESS_EVENT));
+                }
+            })
+            .count();
+
+        let rejected: Vec<_> = posts.iter().flat_map(|posts| posts.rejects().totals()).collect();
+        for rejected in &rejected {
+            warn!("Session {} skipped {} posts rows: {}", session_id, rejected.rows, rejected.reason);
+        }
+        if !rejected.is_empty() {
+            // Confirmed like the results, unlike progress events that may be lost
+            let results = BinaryExchange::new(connection.get_direct_exchange(), Some(RESULTS_QUEUE_NAME.to_string()), 1, 1);
+            let mut results = BufExchange::new(results, session_id);
+            results.send(&Message::RowsRejected(rejected))?;
+            results.flush()?;
+        }
+        for key in branches::end_of_stream_keys(FileKind::Posts, score_extractors) {
+            exchange.end_of_stream_with_key(&key)?;
+        }
+        let remaining_rows = published as u64 % ROWS_PER_PROGRESS_EVENT;
+        progress.publish(session_id, ProgressUpdate::RowsIngested(remaining_rows));
+        progress.publish(session_id, ProgressUpdate::StreamFinished);
+
+        info!("Published {} posts of session {}", published, session_id);
     }
     connection.close()
 }
@@ -1,11 +1,12 @@
 use amiquip::Result;
 use log::{info, warn};
-use std::collections::HashMap;
-use tp2::messages::Message;
+use std::collections::{BTreeMap, HashMap};
+use tp2::messages::{MemeCandidate, Message};
 use tp2::middleware::message_processor::MessageProcessor;
 use tp2::middleware::service::{init, RabbitService};
 use tp2::middleware::RabbitExchange;
-use tp2::{Config, FILTERED_POST_ID_SENTIMENT_QUEUE_NAME, POST_SENTIMENT_MEAN_QUEUE_NAME};
+use tp2::top_k::TopK;
+use tp2::{Config, FILTERED_POST_ID_SENTIMENT_QUEUE_NAME, POST_EXTRACTED_URL_QUEUE_NAME};
 use tp2::health_checker::health_answerer::HealthAnswerer;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -32,18 +33,24 @@ fn main() -> Result<()> {
 }
 
 fn run_service(config: Config) -> Result<()> {
-    let mut processor = PostSentimentCalculator::default();
-    let mut service = RabbitService::new(config, &mut processor);
+    let processor = PostSentimentCalculator {
+        candidates: config.best_meme_candidates.max(config.best_memes),
+        post_sentiments_map: HashMap::new(),
+    };
+    let mut service = RabbitService::new(config, processor);
     service.run(FILTERED_POST_ID_SENTIMENT_QUEUE_NAME, None)
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 struct PostSentimentCalculator {
-    post_sentiments_map: HashMap<String, (f32, i32)>,
+    /// Posts of each group sent as best meme candidates
+    candidates: usize,
+    /// Sentiment sum and comment count of each post, by group
+    post_sentiments_map: HashMap<String, HashMap<String, (f32, i32)>>,
 }
 
 impl MessageProcessor for PostSentimentCalculator {
-    type State = HashMap<String, (f32, i32)>;
+    type State = HashMap<String, HashMap<String, (f32, i32)>>;
 
     fn set_state(&mut self, state: Self::State) {
         self.post_sentiments_map = state;
@@ -55,8 +62,8 @@ impl MessageProcessor for PostSentimentCalculator {
 
     fn process_message(&mut self, message: Message) -> Option<Message> {
         match message {
-            Message::PostIdSentiment(post_id, sentiment) => {
-                let value = self.post_sentiments_map.entry(post_id).or_insert((0.0, 0));
+            Message::PostIdSentiment(group, post_id, sentiment) => {
+                let value = self.post_sentiments_map.entry(group).or_default().entry(post_id).or_insert((0.0, 0));
                 value.0 += sentiment;
                 value.1 += 1;
             }
@@ -67,11 +74,16 @@ impl MessageProcessor for PostSentimentCalculator {
         None
     }
 
-    fn on_stream_finished(&self) -> Option<Message> {
-        info!("Stream finished, {} sentiments", self.post_sentiments_map.len());
-        let post_sentiment = get_highest_post_sentiment(&self.post_sentiments_map);
-        info!("Sending highest post sentiment {:?}", post_sentiment);
-        Some(Message::PostIdSentiment(post_sentiment.0, post_sentiment.1))
+    fn on_stream_finished(&self) -> Vec<Message> {
+        info!("Stream finished, sentiments of {} groups", self.post_sentiments_map.len());
+        // Sent as a single message, so the filter knows every candidate once it gets any
+        let candidates: BTreeMap<String, Vec<(MemeCandidate, f32)>> = self
+            .post_sentiments_map
+            .iter()
+            .map(|(group, sentiments)| (group.clone(), highest_post_sentiments(sentiments, self.candidates)))
+            .collect();
+        info!("Sending highest post sentiments {:?}", candidates);
+        vec![Message::BestMemeCandidates(candidates)]
     }
 
     fn send_process_output<E: RabbitExchange>(
@@ -79,20 +91,21 @@ impl MessageProcessor for PostSentimentCalculator {
         exchange: &mut E,
         message: Message,
     ) -> Result<()> {
-        exchange.send_with_key(&message, POST_SENTIMENT_MEAN_QUEUE_NAME)?;
-        exchange.send_with_key(&Message::Confirmed, POST_SENTIMENT_MEAN_QUEUE_NAME)
+        // The best meme filter reads the best post ids from the same queue as the urls
+        exchange.send_with_key(&message, POST_EXTRACTED_URL_QUEUE_NAME)?;
+        exchange.send_with_key(&message.confirm(), POST_EXTRACTED_URL_QUEUE_NAME)
     }
 }
 
-fn get_highest_post_sentiment(sentiment_map: &HashMap<String, (f32, i32)>) -> (String, f32) {
-    let mut highest_id = "".to_string();
-    let mut highest = -1.0;
+/// The `k` posts with the highest mean sentiment, best first
+fn highest_post_sentiments(sentiment_map: &HashMap<String, (f32, i32)>, k: usize) -> Vec<(MemeCandidate, f32)> {
+    let mut highest = TopK::new(k);
     for (id, sentiment) in sentiment_map.iter() {
-        let sentiment_mean = sentiment.0 / sentiment.1 as f32;
-        if sentiment_mean > highest {
-            highest = sentiment_mean;
-            highest_id = id.clone();
-        }
+        let candidate = MemeCandidate {
+            id: id.clone(),
+            comments: sentiment.1,
+        };
+        highest.push(candidate, sentiment.0 / sentiment.1 as f32);
     }
-    (highest_id, highest)
+    highest.into_ranking()
 }
@@ -1,15 +1,11 @@
 use amiquip::Result;
 use envconfig::Envconfig;
-use log::{info, warn};
+use log::warn;
 use std::collections::HashSet;
-use std::ops::Add;
 use tp2::messages::Message;
 use tp2::middleware::message_processor::MessageProcessor;
 use tp2::middleware::service::RabbitService;
-use tp2::{
-    Config, FILTERED_POST_ID_SENTIMENT_QUEUE_NAME, POST_ID_SENTIMENT_QUEUE_NAME,
-    POST_ID_WITH_URL_QUEUE_NAME,
-};
+use tp2::{Config, FILTERED_POST_ID_SENTIMENT_QUEUE_NAME, POST_ID_SENTIMENT_QUEUE_NAME};
 use tp2::health_checker::health_answerer::HealthAnswerer;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -39,34 +35,35 @@ fn main() -> Result<()> {
 }
 
 fn run_service(config: Config) -> Result<()> {
-    info!("Getting post ids with url");
-    let ids = get_posts_ids_with_url(&config)?;
-    info!("Filtering sentiments with url, got {} ids", ids.len());
-    let mut processor = PostSentimentFilter { ids };
-    let consumer_transaction_log_path = config.transaction_log_path.clone().add(".subservice");
-    let mut service = RabbitService::new(config, &mut processor);
-    std::thread::sleep(std::time::Duration::from_secs(1));
+    let processor = PostSentimentFilter::default();
+    let mut service = RabbitService::new(config, processor);
     service.run(
         POST_ID_SENTIMENT_QUEUE_NAME,
         Some(FILTERED_POST_ID_SENTIMENT_QUEUE_NAME.to_string()),
-    )?;
-    std::fs::remove_file(consumer_transaction_log_path);
-    Ok(())
+    )
 }
 
+/// Comment sentiments and ids of posts with url arrive through the same queue. Sentiments
+/// of posts not known to have an url are kept until the stream finishes.
+#[derive(Clone, Default)]
 struct PostSentimentFilter {
     ids: HashSet<String>,
+    pending_sentiments: Vec<(String, String, f32)>,
 }
 
 impl MessageProcessor for PostSentimentFilter {
-    type State = HashSet<String>;
+    type State = (HashSet<String>, Vec<(String, String, f32)>);
 
     fn process_message(&mut self, message: Message) -> Option<Message> {
         match message {
-            Message::PostIdSentiment(post_id, sentiment) => {
+            Message::PostIdSentiment(group, post_id, sentiment) => {
                 if self.ids.contains(&post_id) {
-                    return Some(Message::PostIdSentiment(post_id, sentiment));
+                    return Some(Message::PostIdSentiment(group, post_id, sentiment));
                 }
+                self.pending_sentiments.push((group, post_id, sentiment));
+            }
+            Message::PostUrl(_, id, _) => {
+                self.ids.insert(id);
             }
             _ => {
                 warn!("Invalid message arrived");
@@ -74,41 +71,20 @@ impl MessageProcessor for PostSentimentFilter {
         }
         None
     }
-}
 
-#[derive(Default)]
-struct PostIdWithUrlConsumer {
-    ids: HashSet<String>,
-}
-
-impl MessageProcessor for PostIdWithUrlConsumer {
-    type State = HashSet<String>;
+    fn on_stream_finished(&self) -> Vec<Message> {
+        self.pending_sentiments
+            .iter()
+            .filter(|(_, post_id, _)| self.ids.contains(post_id))
+            .map(|(group, post_id, sentiment)| Message::PostIdSentiment(group.clone(), post_id.clone(), *sentiment))
+            .collect()
+    }
 
     fn set_state(&mut self, state: Self::State) {
-        self.ids = state;
+        (self.ids, self.pending_sentiments) = state;
     }
 
     fn get_state(&self) -> Option<Self::State> {
-        Some(self.ids.clone())
+        Some((self.ids.clone(), self.pending_sentiments.clone()))
     }
-
-    fn process_message(&mut self, message: Message) -> Option<Message> {
-        match message {
-            Message::PostUrl(id, _) => {
-                self.ids.insert(id);
-            }
-            _ => {
-                warn!("Invalid message arrived");
-            }
-        }
-        None
-    }
-}
-
-fn get_posts_ids_with_url(config: &Config) -> Result<HashSet<String>> {
-    let config = config.clone();
-    let mut processor = PostIdWithUrlConsumer::default();
-    let mut service = RabbitService::new_subservice(config, &mut processor);
-    service.run(POST_ID_WITH_URL_QUEUE_NAME, None)?;
-    Ok(processor.ids.clone())
 }
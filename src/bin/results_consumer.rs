@@ -1,10 +1,13 @@
 use amiquip::{Connection, ConsumerOptions, QueueDeclareOptions, Result};
 use log::{debug, error, info};
-use std::io::Write;
+use std::collections::HashMap;
+use tp2::jobs::JobResults;
 use tp2::messages::Message;
+use tp2::protocol::GroupBy;
 use tp2::middleware::buf_consumer::BufConsumer;
 use tp2::middleware::consumer::DeliveryConsumer;
 use tp2::middleware::service::init;
+use tp2::output::{self, OutputFormat};
 use tp2::{Config, RESULTS_QUEUE_NAME};
 use tp2::health_checker::health_answerer::HealthAnswerer;
 use std::sync::Arc;
@@ -16,25 +19,20 @@ use tp2::health_checker::health_answerer_handler::HealthAnswerHandler;
 fn main() -> Result<()> {
     let env_config = init();
     let output_path =
-        envconfig::load_var_with_default("OUTPUT_PATH", None, "data/output.txt").unwrap();
+        envconfig::load_var_with_default("OUTPUT_PATH", None, "data/output").unwrap();
+    let output_format: OutputFormat =
+        envconfig::load_var_with_default("OUTPUT_FORMAT", None, "json").unwrap();
     let shutdown = Arc::new(AtomicBool::new(false));
     let health_answerer = HealthAnswerer::new("0.0.0.0:6789", shutdown.clone());
     let mut health_answerer_handler = HealthAnswerHandler::new(shutdown.clone());
     let health_answerer_thread = thread::spawn(move || {health_answerer.run(&mut health_answerer_handler)});
-    run_service(env_config, output_path)?;
+    run_service(env_config, output_path, output_format)?;
     shutdown.store(true, Ordering::Relaxed);
     health_answerer_thread.join().expect("Failed to join health_answerer_thread");
     Ok(())
 }
 
-#[derive(Debug, Default)]
-struct Results {
-    best_meme: String,
-    score_mean: f32,
-    college_posts: Vec<String>,
-}
-
-fn run_service(config: Config, output_path: String) -> Result<()> {
+fn run_service(config: Config, output_path: String, output_format: OutputFormat) -> Result<()> {
     let host_addr = format!(
         "amqp://{}:{}@{}:{}",
         config.user, config.pass, config.server_host, config.server_port
@@ -50,35 +48,42 @@ fn run_service(config: Config, output_path: String) -> Result<()> {
     };
     let queue = channel.queue_declare(RESULTS_QUEUE_NAME, options)?;
 
-    // Query results
-    let mut results = Results::default();
-    let mut data_received = (false, false, false);
+    // Query results of every session in progress
+    let mut sessions: HashMap<u64, (JobResults, (bool, bool, bool))> = HashMap::new();
     let consumer = queue.consume(ConsumerOptions::default())?;
     let consumer = DeliveryConsumer::new(consumer);
     let buf_consumer = BufConsumer::new(consumer);
     info!("Starting iteration");
     for compound_delivery in buf_consumer {
+        let session_id = compound_delivery.session_id;
+        let (results, data_received) = sessions.entry(session_id).or_default();
         for message in compound_delivery.data {
             match message {
-                Message::PostScoreMean(mean) => {
-                    info!("got mean: {:?}", mean);
-                    results.score_mean = mean;
+                Message::PostScoreStats(stats) => {
+                    info!("got score stats: {:?}", stats);
+                    for (group, stats) in stats {
+                        results.group_mut(&group).score_stats = Some(stats);
+                    }
                     data_received.0 = true;
                 }
-                Message::PostUrl(id, url) => {
-                    info!("got best meme url: {:?}, {}", url, id);
-                    results.best_meme = url;
+                Message::BestMemes(memes) => {
+                    info!("got best memes: {:?}", memes);
+                    for (group, memes) in memes {
+                        results.group_mut(&group).best_memes = Some(memes);
+                    }
                     data_received.1 = true;
                 }
-                Message::CollegePostUrl(url) => {
-                    results.college_posts.push(url);
+                Message::CollegePostUrl(group, url) => {
+                    results.group_mut(&group).college_posts.get_or_insert_with(Vec::new).push(url);
                 }
+                Message::CollegeKeywordHits(hits) => results.college_keyword_hits = hits,
                 Message::EndOfStream => {}
                 Message::CollegePostEnded => {
                     info!("College posts ended");
                     data_received.2 = true;
                 }
-                Message::Confirmed  => {}
+                Message::RowsRejected(rejected) => results.rejected.extend(rejected),
+                Message::Confirmed(_) => {}
                 _ => {
                     error!("Invalid message arrived {:?}", message);
                 }
@@ -87,15 +92,25 @@ fn run_service(config: Config, output_path: String) -> Result<()> {
         compound_delivery.msg_delivery.ack(&channel)?;
         compound_delivery.confirm_delivery.ack(&channel)?;
         if data_received.0 && data_received.1 && data_received.2 {
-            break;
+            if let Some((results, _)) = sessions.remove(&session_id) {
+                write_results(&format!("{}.{}", output_path, session_id), results, output_format);
+            }
         }
     }
-    if let Ok(mut file) = std::fs::File::create(output_path) {
-        results.college_posts.sort();
-        write!(file, "Results: {:?}", results).unwrap();
-    } else {
-        error!("Couldn't write results!");
-    }
     info!("Exit");
     connection.close()
 }
+
+fn write_results(output_path: &str, mut results: JobResults, output_format: OutputFormat) {
+    if results.group_by == GroupBy::None {
+        results.global.college_posts.get_or_insert_with(Vec::new);
+    }
+    let groups = std::iter::once(&mut results.global).chain(results.subreddits.values_mut());
+    for college_posts in groups.flat_map(|group| group.college_posts.as_mut()) {
+        college_posts.sort();
+    }
+    match output::write_results(output_path, &results, output_format) {
+        Ok(paths) => info!("Results written to {:?}", paths),
+        Err(e) => error!("Couldn't write results: {:?}", e),
+    }
+}
@@ -3,8 +3,10 @@ use log::warn;
 use tp2::messages::Message;
 use tp2::middleware::message_processor::MessageProcessor;
 use tp2::middleware::service::{init, RabbitService};
+use tp2::stats::ScoreStats;
 use tp2::{Config, POST_SCORES_QUEUE_NAME, POST_SCORE_MEAN_QUEUE_NAME};
 use tp2::health_checker::health_answerer::HealthAnswerer;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
@@ -29,26 +31,44 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-struct ScoreExtractor;
+/// Sends the scores of each delivery as a partial aggregate per group, so the mean calculator
+/// gets a message per delivery instead of one per post. Partials are sent before the delivery is
+/// confirmed, so they never need to be part of the state.
+///
+/// Runs as `SCORE_EXTRACTORS` replicas, each reading its own queue. The post producer spreads
+/// its bulks among them and sends each one an EOS, so the mean calculator needs that many
+/// `PRODUCERS`
+#[derive(Clone, Default)]
+struct ScoreExtractor {
+    partials: BTreeMap<String, ScoreStats>,
+}
 
 impl MessageProcessor for ScoreExtractor {
     type State = ();
     fn process_message(&mut self, message: Message) -> Option<Message> {
         match message {
-            Message::FullPost(post) => Some(Message::PostScore(post.score)),
+            Message::FullPost(post) => self.partials.entry(post.group).or_default().add(post.score as f64),
             _ => {
                 warn!("Invalid message arrived: {:?}", message);
-                None
             }
         }
+        None
+    }
+
+    fn on_batch_finished(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.partials)
+            .into_iter()
+            .map(|(group, partial)| Message::PostScores(group, partial))
+            .collect()
     }
 }
 
 fn run_service(config: Config) -> Result<()> {
-    let mut processor = ScoreExtractor;
-    let mut service = RabbitService::new(config, &mut processor);
+    let replica: usize = envconfig::load_var_with_default("SCORE_EXTRACTOR_REPLICA", None, "0").unwrap();
+    let processor = ScoreExtractor::default();
+    let mut service = RabbitService::new(config, processor);
     service.run(
-        POST_SCORES_QUEUE_NAME,
+        &format!("{}.{}", POST_SCORES_QUEUE_NAME, replica),
         Some(POST_SCORE_MEAN_QUEUE_NAME.to_owned()),
     )
 }
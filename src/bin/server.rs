@@ -1,19 +1,23 @@
-use std::{io, thread};
+use std::{fmt, io, thread};
 use std::net::{TcpListener, TcpStream};
-use std::io::{Error, ErrorKind, Read, Write};
+use std::io::{Error, ErrorKind, Write};
 use std::net::Shutdown::Both;
-use std::sync::Arc;
-use amiquip::{Connection, ConsumerOptions, QueueDeclareOptions, Result};
-use tp2::{Config, RESULTS_QUEUE_NAME};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use amiquip::{Connection, ConsumerMessage, ConsumerOptions, ExchangeDeclareOptions, ExchangeType, FieldTable, QueueDeclareOptions, Result};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use tp2::{Config, PROGRESS_EXCHANGE_NAME, PROGRESS_QUEUE_NAME, RESULTS_QUEUE_NAME};
 use tp2::middleware::buf_consumer::BufConsumer;
 use tp2::middleware::consumer::DeliveryConsumer;
-use tp2::messages::Message;
-use log::{debug, error, info};
+use tp2::messages::{Message, ProgressEvent, ProgressUpdate};
+use tp2::jobs::{Job, JobPhase, JobResults, JobTable};
+use tp2::schema::{self, ColumnAliases};
+use tp2::protocol::{self, Compression, ErrorCode, FileKind, Frame, GroupBy, JobStatus, Progress, Query, QueryResult, StageProgress, InputFormat, UploadEncoding, PROTOCOL_VERSION};
+use log::{debug, error, info, warn};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use envconfig::Envconfig;
-use signal_hook::consts::SIGTERM;
-use signal_hook::iterator::Signals;
+use flate2::write::GzDecoder;
 
 fn main() {
     println!("Server started");
@@ -22,77 +26,307 @@ fn main() {
     std::env::set_var("RUST_LOG", env_config.logging_level.clone());
     env_logger::init();
     let config = ServerConfig::init_from_env().expect("Failed to read env configuration");
-    let mut server = Server::new(config.chunk_size, config.server_address, config.posts_producer_address, config.comments_producer_address);
+    let server = Server::new(config, env_config.column_aliases.clone());
     server.run(&env_config);
 }
 
 const MAX_TRIES_FOR_COMMENT_PRODUCER_CONNECTOR: u64 = 5;
 const SEC_BETWEEN_RETRIES_FOR_COMMENT: u64 = 20;
+/// Progress of a job is sent to its client at most once per interval
+const PROGRESS_FRAME_INTERVAL: Duration = Duration::from_secs(1);
+/// Bytes at the start of a file held back until its first rows are validated
+const VALIDATION_SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Upload rejected by a limit or a validation, answered to the client with its own code
+#[derive(Debug)]
+struct UploadRejected {
+    code: ErrorCode,
+    message: String,
+}
+
+impl fmt::Display for UploadRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for UploadRejected {}
+
+fn reject(code: ErrorCode, message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, UploadRejected { code, message })
+}
 
 pub struct Server {
-    chunk_size: u64,
     server_address: String,
     posts_producer_address: String,
     comments_producer_address: String,
-    invalid_state: bool
+    /// Set while aborted jobs wait for their comments EOS, new clients are rejected meanwhile
+    invalid_state: AtomicBool,
+    results_router: ResultsRouter,
+    job_slots: JobSlots,
+    /// Interrupted uploads waiting for their client to reconnect
+    pending_uploads: Mutex<HashMap<u64, Sender<TcpStream>>>,
+    resume_timeout: Duration,
+    /// Bytes accepted for each uploaded file
+    max_upload_size: u64,
+    max_chunk_size: usize,
+    /// Time a job may spend processing once uploaded before its partial results are returned
+    job_deadline: Duration,
+    job_table: JobTable,
+    /// Running jobs cancelled by a client, they stop at their next chunk or result
+    cancelled_jobs: Mutex<HashSet<u64>>,
+    /// Header names accepted for the uploaded columns, same as the producers'
+    column_aliases: ColumnAliases,
 }
 
 impl Server {
-    pub fn new(chunk_size: u64,
-               server_address: String,
-               posts_producer_address: String,
-               comments_producer_address: String) -> Self {
+    pub fn new(config: ServerConfig, column_aliases: ColumnAliases) -> Self {
         Server{
-            chunk_size,
-            server_address,
-            posts_producer_address,
-            comments_producer_address,
-            invalid_state: false
+            server_address: config.server_address,
+            posts_producer_address: config.posts_producer_address,
+            comments_producer_address: config.comments_producer_address,
+            invalid_state: AtomicBool::new(false),
+            results_router: ResultsRouter::default(),
+            job_slots: JobSlots::new(config.max_concurrent_jobs, config.max_queued_clients),
+            pending_uploads: Mutex::new(HashMap::new()),
+            resume_timeout: Duration::from_secs(config.resume_timeout_sec),
+            max_upload_size: config.max_upload_size,
+            max_chunk_size: config.max_chunk_size,
+            job_deadline: Duration::from_secs(config.job_deadline_sec),
+            job_table: JobTable::load(&config.jobs_path, Duration::from_secs(config.job_retention_sec)),
+            cancelled_jobs: Mutex::new(HashSet::new()),
+            column_aliases,
         }
     }
 
-    pub fn run(&mut self, config: &Config) {
+    pub fn run(&self, config: &Config) {
         let shutdown = Arc::new(AtomicBool::new(false));
         signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&shutdown.clone())).unwrap();
+        // Registered before the router starts, so it doesn't drop their results
+        let resumed_jobs: Vec<_> = self.job_table
+            .with_phase(JobPhase::AwaitingResults)
+            .into_iter()
+            .map(|(job_id, queries)| (job_id, queries, self.results_router.register(job_id)))
+            .collect();
+        let results_router = self.results_router.clone();
+        let router_config = config.clone();
+        let shutdown_router = shutdown.clone();
+        thread::spawn(move || {
+            while !shutdown_router.load(Ordering::Relaxed) {
+                if let Err(e) = results_router.run(&router_config) {
+                    error!("Results router failed: {:?}. Reconnecting", e);
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        });
+        let progress_router = self.results_router.clone();
+        let router_config = config.clone();
+        let shutdown_router = shutdown.clone();
+        thread::spawn(move || {
+            while !shutdown_router.load(Ordering::Relaxed) {
+                if let Err(e) = progress_router.run_progress(&router_config) {
+                    error!("Progress router failed: {:?}. Reconnecting", e);
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        });
 
         let listener = TcpListener::bind(self.server_address.clone()).expect(&*format!("Could not bind to address: {}", self.server_address));
         listener.set_nonblocking(true).expect("Could not set non blocking to true");
-        for client in listener.incoming() {
-            match client {
-                Ok(mut stream) => {
-                    match self.handle_client(&mut stream, config) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("Error while handling client: {:?}", e);
-                            self.answer_not_available(&mut stream);
-                        }
+        // Every client is served on its own thread, all of them are joined before exiting
+        thread::scope(|scope| {
+            for (job_id, queries, results) in resumed_jobs {
+                scope.spawn(move || self.resume_job(job_id, &queries, &results));
+            }
+            let shutdown_injector = shutdown.clone();
+            scope.spawn(move || {
+                let mut next_try = Instant::now();
+                while !shutdown_injector.load(Ordering::Relaxed) {
+                    if Instant::now() >= next_try {
+                        self.inject_pending_eos();
+                        next_try = Instant::now() + Duration::from_secs(SEC_BETWEEN_RETRIES_FOR_COMMENT);
                     }
-                } Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    std::thread::sleep(Duration::from_secs(1));
-                }  Err(e) => {
-                    println!("Failed to accept client. Error: {:?}", e);
+                    thread::sleep(Duration::from_secs(1));
+                }
+            });
+            for client in listener.incoming() {
+                match client {
+                    Ok(stream) => {
+                        scope.spawn(move || self.serve_client(stream));
+                    } Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        std::thread::sleep(Duration::from_secs(1));
+                    }  Err(e) => {
+                        println!("Failed to accept client. Error: {:?}", e);
+                    }
+                }
+                if shutdown.load(Ordering::Relaxed) {
+                    break;
                 }
             }
-            if shutdown.load(Ordering::Relaxed) {
-                break;
+        });
+    }
+
+    fn serve_client(&self, mut stream: TcpStream) {
+        if let Err(e) = stream.set_nonblocking(false) {
+            error!("Could not set client stream as blocking: {:?}", e);
+            return;
+        }
+        match protocol::handshake(&mut stream) {
+            Ok(PROTOCOL_VERSION) => {}
+            Ok(version) => {
+                warn!("Client speaks protocol version {}, expected {}", version, PROTOCOL_VERSION);
+                let _ = stream.shutdown(Both);
+                return;
+            }
+            Err(e) => {
+                error!("Handshake with client failed: {:?}", e);
+                return;
+            }
+        }
+        let (queries, encoding, group_by) = match protocol::read_frame(&mut stream) {
+            Ok(Frame::Start { queries, encoding, group_by }) if !queries.is_empty() => (queries, encoding, group_by),
+            Ok(Frame::Start { .. }) => {
+                self.answer_error(&mut stream, ErrorCode::InvalidRequest, "No queries requested");
+                return;
+            }
+            Ok(Frame::Resume { upload_id }) => {
+                self.resume_upload(stream, upload_id);
+                return;
+            }
+            Ok(Frame::Status { job_id }) => {
+                self.answer_status(&mut stream, job_id);
+                return;
+            }
+            Ok(Frame::Fetch { job_id }) => {
+                self.answer_fetch(&mut stream, job_id);
+                return;
+            }
+            Ok(Frame::Cancel { job_id }) => {
+                self.cancel_job(&mut stream, job_id);
+                return;
+            }
+            Ok(frame) => {
+                self.answer_error(&mut stream, ErrorCode::InvalidRequest, &format!("Expected a job request, got {:?}", frame));
+                return;
+            }
+            Err(e) => {
+                error!("Failed to read client request: {:?}", e);
+                return;
+            }
+        };
+        let _slot = match self.job_slots.acquire(|position| self.answer_queued(&mut stream, position)) {
+            Some(slot) => slot,
+            None => {
+                warn!("Too many clients waiting, rejecting client");
+                self.answer_error(&mut stream, ErrorCode::ServerBusy, "Too many clients waiting, try again later");
+                let _ = stream.shutdown(Both);
+                return;
+            }
+        };
+        match self.handle_client(&mut stream, &queries, encoding, group_by) {
+            Ok(_) => {}
+            Err(e) => {
+                println!("Error while handling client: {:?}", e);
+                let code = match e.get_ref().and_then(|inner| inner.downcast_ref::<UploadRejected>()) {
+                    Some(rejected) => rejected.code,
+                    None => match e.kind() {
+                        ErrorKind::InvalidData => ErrorCode::InvalidRequest,
+                        ErrorKind::Interrupted => ErrorCode::JobCancelled,
+                        _ => ErrorCode::ServerNotAvailable,
+                    },
+                };
+                self.answer_error(&mut stream, code, &e.to_string());
             }
         }
     }
 
-    fn handle_client(&mut self, stream: &mut TcpStream, config: &Config) -> io::Result<()> {
-        if self.invalid_state {
+    fn handle_client(&self, stream: &mut TcpStream, queries: &[Query], encoding: UploadEncoding, group_by: GroupBy) -> io::Result<()> {
+        if self.invalid_state.load(Ordering::Relaxed) {
             error!("System is in an invalid state!");
             return Err(Error::new(ErrorKind::Other, "Server in an invalid state."));
         }
-        println!("Forwarding posts");
-        let mut got_any_error = false;
+        let session_id = self.new_session_id();
+        self.job_table.start(session_id, queries, group_by);
+        protocol::write_frame(stream, &Frame::Ready { upload_id: session_id })?;
+        let results = self.results_router.register(session_id);
+        let result = self.handle_session(stream, session_id, queries, encoding, group_by, &results);
+        self.results_router.unregister(session_id);
+        let cancelled = self.cancelled_jobs.lock().unwrap().remove(&session_id);
+        if let Err(e) = &result {
+            if !cancelled {
+                self.job_table.set_status(session_id, JobStatus::Failed { reason: e.to_string() });
+            }
+            let phase = self.job_table.get(session_id).and_then(|job| job.phase);
+            self.job_table.set_phase(session_id, phase.and_then(JobPhase::aborted));
+        }
+        result
+    }
+
+    /// Random, so clients can't guess the ids of other uploads and jobs to resume, cancel or
+    /// fetch them. Never 0, the session of pipeline wide EOS, nor the id of a known job
+    fn new_session_id(&self) -> u64 {
+        loop {
+            let session_id = rand::random::<u64>();
+            if session_id != 0 && self.job_table.get(session_id).is_none() {
+                return session_id;
+            }
+        }
+    }
+
+    /// Waits for the results of a job the server was awaiting before it restarted, starting from
+    /// the ones saved before it did
+    fn resume_job(&self, job_id: u64, queries: &[Query], results: &Receiver<Message>) {
+        info!("Resuming job {} after a restart", job_id);
+        let group_by = self.job_table.get(job_id).map(|job| job.group_by).unwrap_or_default();
+        match self.wait_for_results(&mut io::sink(), job_id, queries, group_by, results) {
+            Ok(results) => self.job_table.finish(job_id, results),
+            Err(e) => {
+                self.job_table.set_status(job_id, JobStatus::Failed { reason: e.to_string() });
+                self.job_table.set_phase(job_id, None);
+            }
+        }
+        self.results_router.unregister(job_id);
+    }
+
+    /// Sends the comments EOS of aborted jobs the comments producer never got, so the pipeline
+    /// can close their sessions. Clients are rejected while any is pending
+    fn inject_pending_eos(&self) {
+        for (session_id, queries) in self.job_table.with_phase(JobPhase::CommentsEosPending) {
+            let injected = TcpStream::connect(&self.comments_producer_address).and_then(|mut producer| {
+                producer.write_all(&Self::session_header(session_id, &queries, InputFormat::Auto, GroupBy::None))?;
+                producer.shutdown(Both)
+            });
+            match injected {
+                Ok(()) => {
+                    info!("Injected comments EOS of aborted job {}", session_id);
+                    self.job_table.set_phase(session_id, None);
+                }
+                Err(e) => warn!("Failed to inject comments EOS of job {}: {:?}", session_id, e),
+            }
+        }
+        let pending = !self.job_table.with_phase(JobPhase::CommentsEosPending).is_empty();
+        self.invalid_state.store(pending, Ordering::Relaxed);
+    }
+
+    fn handle_session(
+        &self,
+        stream: &mut TcpStream,
+        session_id: u64,
+        queries: &[Query],
+        encoding: UploadEncoding,
+        group_by: GroupBy,
+        results: &Receiver<Message>,
+    ) -> io::Result<()> {
+        println!("Forwarding posts of session {}", session_id);
+        let header = Self::session_header(session_id, queries, encoding.format, group_by);
+        let mut upload_error = None;
         // Can safely exit if post_producer connection fails
         let mut post_producer_stream = TcpStream::connect(self.posts_producer_address.clone())?;
-        match self.forward_file(stream, &mut post_producer_stream) {
+        match self.forward_session(stream, &mut post_producer_stream, session_id, &header, encoding, FileKind::Posts) {
             Ok(_) => {}
             Err(e) => {
-                got_any_error = true;
                 error!("Got error {:?} while forwarding file", e);
+                upload_error = Some(e);
             }
         };
         // Don't care what error got as long as socket closes
@@ -107,12 +341,17 @@ impl Server {
             match TcpStream::connect(self.comments_producer_address.clone()) {
                 Ok(mut comment_producer_stream) => {
                     connected_to_comment_producer = true;
-                    match self.forward_file(stream, &mut comment_producer_stream) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            got_any_error = true;
-                            error!("Got error {:?} while forwarding file", e);
+                    // Once an upload failed the client stream can't be trusted, just close the producer
+                    if upload_error.is_none() {
+                        match self.forward_session(stream, &mut comment_producer_stream, session_id, &header, encoding, FileKind::Comments) {
+                            Ok(_) => {}
+                            Err(e) => {
+                                error!("Got error {:?} while forwarding file", e);
+                                upload_error = Some(e);
+                            }
                         }
+                    } else {
+                        let _ = self.open_session(&mut comment_producer_stream, session_id, &header, FileKind::Comments);
                     }
                     // Same as before
                     let _ = comment_producer_stream.shutdown(Both);
@@ -122,89 +361,556 @@ impl Server {
                     tries -= 1;
                     if tries == 0 {
                         // If reached this point, something REALLY bad happened and the whole system
-                        // is in an invalid state...
-                        self.invalid_state = true;
+                        // is in an invalid state until the comments EOS gets injected
+                        self.invalid_state.store(true, Ordering::Relaxed);
                         return Err(Error::new(ErrorKind::Other, "System in an invalid state."));
-                        error!("System in an invalid state. We should never reach this point right? RIGHT!?");
                     }
                     thread::sleep(Duration::from_secs(SEC_BETWEEN_RETRIES_FOR_COMMENT));
                 }
             }
         }
+        // Producers already ended the session stream, so the pipeline closes it on its own and
+        // the router drops its results
+        if let Some(e) = upload_error {
+            return Err(e);
+        }
+        self.job_table.set_status(session_id, JobStatus::Processing);
+        self.job_table.set_phase(session_id, Some(JobPhase::AwaitingResults));
+        // The client may leave now and fetch the results later
+        let _ = protocol::write_frame(stream, &Frame::Submitted { job_id: session_id });
         println!("Waiting for response");
-        return match self.wait_for_results(config) {
-            Ok(results) => {
-                if got_any_error {
-                    return Err(Error::new(ErrorKind::Other, "Something went wrong. Check the logs. Answering not available"));
-                }
-                println!("Best meme received: {:?}", results.best_meme);
-                println!("Score mean received: {:?}", results.score_mean);
-                println!("College posts received: {:?}", results.college_posts.len());
-                self.send_results_to_client(stream, &results)?;
-                Ok(())
-            }
-            Err(_) => {
-                Err(Error::new(ErrorKind::Other, "Failed to wait for results. Server not available"))
-            }
+        let results = self.wait_for_results(stream, session_id, queries, group_by, results)?;
+        for (group, group_results) in results.groups() {
+            println!("Best memes received{}: {:?}", group_label(group), group_results.best_memes);
+            println!("Score stats received{}: {:?}", group_label(group), group_results.score_stats);
+            println!("College posts received{}: {:?}", group_label(group), group_results.college_posts.as_ref().map(Vec::len));
         }
+        for rejected in &results.rejected {
+            println!("Job {} skipped {} {:?} rows: {}", session_id, rejected.rows, rejected.file, rejected.reason);
+        }
+        if !results.missing.is_empty() {
+            println!("Job {} returned partial results, missing: {:?}", session_id, results.missing);
+        }
+        self.job_table.finish(session_id, results.clone());
+        if let Err(e) = self.send_results_to_client(stream, &results) {
+            warn!("Couldn't send results of job {}, the client can fetch them later: {:?}", session_id, e);
+        }
+        Ok(())
     }
 
-    fn forward_file(&self, from: &mut TcpStream, to: &mut TcpStream) -> io::Result<()> {
+    /// Session id followed by the queries it asked for, so producers only feed the branches they
+    /// need, the format of its files and how its results are grouped
+    fn session_header(session_id: u64, queries: &[Query], format: InputFormat, group_by: GroupBy) -> [u8; 11] {
+        let mut header = [0u8; 11];
+        header[..8].copy_from_slice(&session_id.to_be_bytes());
+        header[8] = Query::to_mask(queries);
+        header[9] = format.to_byte();
+        header[10] = group_by.to_byte();
+        header
+    }
 
-        let file_size = self.read_file_size(from)?;
-        println!("file_size is {:?}", file_size);
+    /// Sends the session header to a producer and journals that it got the session
+    fn open_session(&self, to: &mut TcpStream, session_id: u64, header: &[u8], file: FileKind) -> io::Result<()> {
+        to.write_all(header)?;
+        let phase = match file {
+            FileKind::Posts => JobPhase::ForwardingPosts,
+            FileKind::Comments => JobPhase::ForwardingComments,
+        };
+        self.job_table.set_phase(session_id, Some(phase));
+        Ok(())
+    }
+
+    /// Forwards a client file to a producer, preceded by the session header
+    fn forward_session(
+        &self,
+        from: &mut TcpStream,
+        to: &mut TcpStream,
+        session_id: u64,
+        header: &[u8],
+        encoding: UploadEncoding,
+        file: FileKind,
+    ) -> io::Result<()> {
+        self.open_session(to, session_id, header, file)?;
+        self.forward_file(from, to, session_id, encoding, file)
+    }
+
+    /// Forwards upload chunks, decompressed, acknowledging each one. If the client disconnects,
+    /// waits for it to resume from the last forwarded byte
+    fn forward_file(&self, from: &mut TcpStream, to: &mut TcpStream, session_id: u64, encoding: UploadEncoding, file: FileKind) -> io::Result<()> {
         let mut sent = 0;
+        // Size limits apply to the decompressed file too, so small uploads can't blow up
+        let mut decompressed = 0;
+        let mut decoder = UploadDecoder::new(encoding.compression);
+        // Start of the file, held back until its first rows are validated
+        let mut sample = Some(Vec::new());
+        let max_frame_size = self.max_chunk_size.saturating_add(protocol::UPLOAD_FRAME_OVERHEAD);
         loop {
-            let chunk_to_read = if file_size - sent > self.chunk_size { self.chunk_size }  else {file_size - sent};
-            let chunk = self.read_chunk(from, chunk_to_read)?;
-            to.write_all(&chunk)?;
-            sent += chunk_to_read as u64;
-            if sent >= file_size { break; }
+            self.check_cancelled(session_id)?;
+            let frame = match protocol::read_frame_limited(from, max_frame_size) {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == ErrorKind::FileTooLarge => {
+                    return Err(reject(ErrorCode::ChunkTooLarge, format!("Chunks can't exceed {} bytes", self.max_chunk_size)));
+                }
+                Err(e) if e.kind() == ErrorKind::InvalidData => return Err(e),
+                Err(e) => {
+                    warn!("Upload {} interrupted: {:?}", session_id, e);
+                    *from = self.wait_for_resume(session_id, file, sent)?;
+                    continue;
+                }
+            };
+            match frame {
+                Frame::Upload { file: chunk_file, offset, data } if chunk_file == file && offset == sent => {
+                    if data.len() > self.max_chunk_size {
+                        return Err(reject(ErrorCode::ChunkTooLarge, format!("Chunks can't exceed {} bytes", self.max_chunk_size)));
+                    }
+                    if sent + data.len() as u64 > self.max_upload_size {
+                        return Err(reject(ErrorCode::UploadTooLarge, format!("Files can't exceed {} bytes", self.max_upload_size)));
+                    }
+                    let plain = decoder
+                        .decode(&data, self.max_upload_size - decompressed)
+                        .map_err(|e| match e.kind() {
+                            ErrorKind::FileTooLarge => reject(ErrorCode::UploadTooLarge, format!("Files can't exceed {} bytes decompressed", self.max_upload_size)),
+                            _ => reject(ErrorCode::InvalidCompression, format!("Invalid {:?} data: {}", encoding.compression, e)),
+                        })?;
+                    decompressed += plain.len() as u64;
+                    match sample.as_mut() {
+                        Some(buffer) => {
+                            buffer.extend_from_slice(&plain);
+                            if data.is_empty() || buffer.len() >= VALIDATION_SAMPLE_SIZE {
+                                schema::validate(file, buffer, data.is_empty(), encoding.format, &self.column_aliases)
+                                    .map_err(|message| reject(ErrorCode::InvalidCsv, message))?;
+                                to.write_all(buffer)?;
+                                sample = None;
+                            }
+                        }
+                        None => to.write_all(&plain)?,
+                    }
+                    sent += data.len() as u64;
+                    // A lost ack shows up as an error on the next read
+                    let _ = protocol::write_frame(from, &Frame::UploadAck { file, offset: sent });
+                    if data.is_empty() {
+                        break;
+                    }
+                }
+                frame => {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Expected {:?} upload at offset {}, got {:?}", file, sent, frame)));
+                }
+            }
         }
-        println!("Finished sending {:?}", file_size);
+        println!("Finished sending {:?}, {:?} decompressed", sent, decompressed);
         Ok(())
     }
 
-    fn read_file_size(&self, stream: &mut TcpStream) -> io::Result<u64> {
-        let mut file_size_buff = [0u8; 8];
-        let mut n_received = 0;
-        loop {
-            let n_bytes = stream.read(&mut file_size_buff[n_received..])?;
-            if n_bytes == 0 {
-                return Err(Error::new(ErrorKind::UnexpectedEof, "Stream closed"));
+    fn wait_for_resume(&self, upload_id: u64, file: FileKind, offset: u64) -> io::Result<TcpStream> {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        self.pending_uploads.lock().unwrap().insert(upload_id, sender);
+        let resumed = receiver.recv_timeout(self.resume_timeout);
+        self.pending_uploads.lock().unwrap().remove(&upload_id);
+        let mut stream = resumed.map_err(|_| Error::new(ErrorKind::TimedOut, "Client didn't resume the upload"))?;
+        info!("Resuming upload {} from {:?} offset {}", upload_id, file, offset);
+        protocol::write_frame(&mut stream, &Frame::Resumed { file, offset })?;
+        Ok(stream)
+    }
+
+    /// Hands the stream over to the session waiting for it
+    fn resume_upload(&self, mut stream: TcpStream, upload_id: u64) {
+        let pending_upload = self.pending_uploads.lock().unwrap().remove(&upload_id);
+        match pending_upload {
+            Some(sender) => {
+                if let Err(e) = sender.send(stream) {
+                    error!("Upload {} stopped waiting for its client", upload_id);
+                    self.answer_error(&mut e.into_inner(), ErrorCode::UnknownUpload, "Upload is not waiting to be resumed");
+                }
+            }
+            None => {
+                self.answer_error(&mut stream, ErrorCode::UnknownUpload, "Upload is not waiting to be resumed");
             }
-            n_received += n_bytes;
-            if n_received == file_size_buff.len() {
+        }
+    }
+
+    /// Collects the results of the queries of a session, streaming its progress to the client
+    /// meanwhile. If the job deadline expires first, returns the results that did arrive
+    fn wait_for_results(
+        &self,
+        stream: &mut impl Write,
+        session_id: u64,
+        queries: &[Query],
+        group_by: GroupBy,
+        results: &Receiver<Message>,
+    ) -> io::Result<JobResults> {
+        // Results saved before a restart, missing the queries that were still pending
+        let (mut session_results, mut pending) = match self.job_table.results(session_id) {
+            Some(saved) => {
+                let pending: BTreeSet<Query> = saved.missing.iter().copied().collect();
+                (JobResults { missing: vec![], ..saved }, pending)
+            }
+            None => {
+                let mut session_results = JobResults { group_by, ..Default::default() };
+                if queries.contains(&Query::CollegePosts) && group_by == GroupBy::None {
+                    session_results.global.college_posts = Some(vec![]);
+                }
+                (session_results, queries.iter().copied().collect())
+            }
+        };
+        // Whether results arrived since they were last saved
+        let mut unsaved = false;
+        let mut progress = SessionProgress::default();
+        let mut last_progress_frame = Instant::now();
+        let deadline = Instant::now() + self.job_deadline;
+        info!("Starting iteration");
+        while !pending.is_empty() {
+            self.check_cancelled(session_id)?;
+            if last_progress_frame.elapsed() >= PROGRESS_FRAME_INTERVAL {
+                // The client may have left already, its results are kept anyway
+                for frame in progress.take_changes() {
+                    let _ = protocol::write_frame(stream, &Frame::Progress(frame));
+                }
+                last_progress_frame = Instant::now();
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                warn!("Job deadline expired, missing queries: {:?}", pending);
                 break;
             }
+            // The router acks the results once they are handed over, so they are saved as soon
+            // as the ones routed together are in
+            if unsaved && results.is_empty() {
+                session_results.missing = pending.iter().copied().collect();
+                self.job_table.save_results(session_id, &session_results);
+                unsaved = false;
+            }
+            let message = match results.recv_timeout(remaining.min(PROGRESS_FRAME_INTERVAL)) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::other("Failed to wait for results. Server not available"));
+                }
+            };
+            unsaved |= !matches!(message, Message::Progress(_) | Message::Confirmed(_) | Message::EndOfStream);
+            match message {
+                // Skipped branches still flush their empty results when the stream ends
+                Message::PostScoreStats(_) if !queries.contains(&Query::ScoreMean) => {}
+                Message::BestMemes(_) if !queries.contains(&Query::BestMeme) => {}
+                Message::CollegePostUrl(_, _) | Message::CollegePostEnded | Message::CollegeKeywordHits(_)
+                    if !queries.contains(&Query::CollegePosts) => {}
+                Message::PostScoreStats(stats) => {
+                    info!("got score stats: {:?}", stats);
+                    for (group, stats) in stats {
+                        session_results.group_mut(&group).score_stats = Some(stats);
+                    }
+                    pending.remove(&Query::ScoreMean);
+                }
+                Message::BestMemes(memes) => {
+                    info!("got best memes: {:?}", memes);
+                    for (group, memes) in memes {
+                        session_results.group_mut(&group).best_memes = Some(memes);
+                    }
+                    pending.remove(&Query::BestMeme);
+                }
+                Message::CollegePostUrl(group, url) => {
+                    session_results.group_mut(&group).college_posts.get_or_insert_with(Vec::new).push(url);
+                }
+                Message::CollegeKeywordHits(hits) => session_results.college_keyword_hits = hits,
+                Message::EndOfStream => {}
+                Message::CollegePostEnded => {
+                    info!("College posts ended");
+                    pending.remove(&Query::CollegePosts);
+                }
+                Message::RowsRejected(rejected) => session_results.rejected.extend(rejected),
+                Message::Confirmed(_) => {}
+                Message::Progress(event) => progress.update(event),
+                _ => {
+                    error!("Invalid message arrived {:?}", message);
+                }
+            }
         }
-        return Ok(u64::from_be_bytes(file_size_buff));
+        session_results.missing = pending.into_iter().collect();
+        info!("Exit");
+        Ok(session_results)
+    }
+
+    fn send_results_to_client(&self, stream: &mut TcpStream, results: &JobResults) -> io::Result<()>{
+        for (group, group_results) in results.groups() {
+            if let Some(best_memes) = group_results.best_memes.clone() {
+                protocol::write_frame(stream, &Frame::Result(QueryResult::BestMemes(group.to_string(), best_memes)))?;
+            }
+            if let Some(score_stats) = group_results.score_stats.clone() {
+                protocol::write_frame(stream, &Frame::Result(QueryResult::ScoreStats(group.to_string(), score_stats)))?;
+            }
+            for college_post in group_results.college_posts.iter().flatten() {
+                let result = QueryResult::CollegePost(group.to_string(), college_post.clone());
+                protocol::write_frame(stream, &Frame::Result(result))?;
+            }
+        }
+        if !results.college_keyword_hits.is_empty() {
+            let result = QueryResult::CollegeKeywordHits(results.college_keyword_hits.clone());
+            protocol::write_frame(stream, &Frame::Result(result))?;
+        }
+        if !results.rejected.is_empty() {
+            protocol::write_frame(stream, &Frame::Rejected { rows: results.rejected.clone() })?;
+        }
+        if !results.missing.is_empty() {
+            protocol::write_frame(stream, &Frame::Partial { missing: results.missing.clone() })?;
+        }
+        protocol::write_frame(stream, &Frame::Finished)
+    }
+
+    fn answer_status(&self, stream: &mut TcpStream, job_id: u64) {
+        match self.job_table.get(job_id) {
+            Some(job) => {
+                let _ = protocol::write_frame(stream, &Frame::JobStatus { job_id, status: job.status });
+            }
+            None => self.answer_error(stream, ErrorCode::UnknownJob, &format!("Unknown job {}", job_id)),
+        }
+    }
+
+    fn answer_fetch(&self, stream: &mut TcpStream, job_id: u64) {
+        match self.job_table.get(job_id) {
+            Some(Job { status: JobStatus::Finished, .. }) => match self.job_table.results(job_id) {
+                Some(results) => {
+                    if let Err(e) = self.send_results_to_client(stream, &results) {
+                        error!("Failed to send results of job {}: {:?}", job_id, e);
+                    }
+                }
+                None => self.answer_error(stream, ErrorCode::ServerNotAvailable, &format!("Results of job {} were lost", job_id)),
+            },
+            Some(Job { status: JobStatus::Failed { reason }, .. }) => {
+                self.answer_error(stream, ErrorCode::ServerNotAvailable, &reason);
+            }
+            Some(Job { status: JobStatus::Cancelled, .. }) => {
+                self.answer_error(stream, ErrorCode::JobCancelled, &format!("Job {} was cancelled", job_id));
+            }
+            Some(_) => self.answer_error(stream, ErrorCode::JobNotFinished, &format!("Job {} didn't finish yet", job_id)),
+            None => self.answer_error(stream, ErrorCode::UnknownJob, &format!("Unknown job {}", job_id)),
+        }
+    }
+
+    fn check_cancelled(&self, job_id: u64) -> io::Result<()> {
+        if self.cancelled_jobs.lock().unwrap().contains(&job_id) {
+            return Err(Error::new(ErrorKind::Interrupted, format!("Job {} was cancelled", job_id)));
+        }
+        Ok(())
+    }
+
+    /// Marks a running job as cancelled. Its session stops at the next chunk or result, but
+    /// still sends its end of stream so the pipeline closes it
+    fn cancel_job(&self, stream: &mut TcpStream, job_id: u64) {
+        match self.job_table.get(job_id) {
+            Some(Job { status: JobStatus::Uploading | JobStatus::Processing, .. }) => {
+                self.cancelled_jobs.lock().unwrap().insert(job_id);
+                self.job_table.set_status(job_id, JobStatus::Cancelled);
+                info!("Job {} cancelled", job_id);
+                let _ = protocol::write_frame(stream, &Frame::JobStatus { job_id, status: JobStatus::Cancelled });
+            }
+            Some(_) => self.answer_error(stream, ErrorCode::JobAlreadyFinished, &format!("Job {} already finished", job_id)),
+            None => self.answer_error(stream, ErrorCode::UnknownJob, &format!("Unknown job {}", job_id)),
+        }
+    }
+
+    fn answer_queued(&self, stream: &mut TcpStream, position: usize) {
+        let _ = protocol::write_frame(stream, &Frame::Progress(Progress::Queued { position }));
+        println!("Client queued at position {}", position);
     }
 
-    fn read_chunk(&self, stream: &mut TcpStream, n: u64) -> io::Result<Vec<u8>> {
-        let mut received = vec![0; n as usize];
-        let mut n_received = 0;
+    fn answer_error(&self, stream: &mut TcpStream, code: ErrorCode, message: &str) {
+        let _ = protocol::write_frame(stream, &Frame::Error { code, message: message.to_string() });
+        println!("Sent error {} to client", code);
+    }
+
+}
+
+/// Limits how many clients are served at once. Clients over the limit wait in arrival order
+/// until a slot frees up, or are rejected if too many are already waiting.
+pub struct JobSlots {
+    queue: Mutex<JobQueue>,
+    changed: Condvar,
+    max_running: usize,
+    max_waiting: usize,
+}
+
+#[derive(Default)]
+struct JobQueue {
+    running: usize,
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+/// Frees its job slot when dropped
+struct JobSlot<'a> {
+    slots: &'a JobSlots,
+}
+
+impl JobSlots {
+    pub fn new(max_running: usize, max_waiting: usize) -> Self {
+        Self {
+            queue: Mutex::new(JobQueue::default()),
+            changed: Condvar::new(),
+            max_running,
+            max_waiting,
+        }
+    }
+
+    /// Blocks until a slot is free, calling `on_queued` every time the queue position changes.
+    /// Returns None if the waiting queue is full
+    fn acquire<F: FnMut(usize)>(&self, mut on_queued: F) -> Option<JobSlot<'_>> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.running < self.max_running && queue.waiting.is_empty() {
+            queue.running += 1;
+            return Some(JobSlot { slots: self });
+        }
+        if queue.waiting.len() >= self.max_waiting {
+            return None;
+        }
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.waiting.push_back(ticket);
+        let mut last_position = 0;
         loop {
-            let n_bytes = stream.read(&mut received[n_received..])?;
-            if n_bytes == 0 {
-                return Err(Error::new(ErrorKind::UnexpectedEof, "Stream closed"));
+            let position = queue.waiting.iter().position(|t| *t == ticket).unwrap_or_default();
+            if position == 0 && queue.running < self.max_running {
+                queue.waiting.pop_front();
+                queue.running += 1;
+                self.changed.notify_all();
+                return Some(JobSlot { slots: self });
             }
-            n_received += n_bytes;
-            if n_received == received.len() {
-                break;
+            if position + 1 != last_position {
+                last_position = position + 1;
+                on_queued(last_position);
+            }
+            queue = self.changed.wait(queue).unwrap();
+        }
+    }
+}
+
+impl Drop for JobSlot<'_> {
+    fn drop(&mut self) {
+        let mut queue = self.slots.queue.lock().unwrap();
+        queue.running -= 1;
+        self.slots.changed.notify_all();
+    }
+}
+
+/// Aggregates the progress events of a session until they are sent to its client
+#[derive(Default)]
+struct SessionProgress {
+    stages: HashMap<String, StageProgress>,
+    changed: BTreeSet<String>,
+}
+
+impl SessionProgress {
+    fn update(&mut self, event: ProgressEvent) {
+        let stage = self.stages.entry(event.stage.clone()).or_default();
+        match event.update {
+            ProgressUpdate::RowsIngested(rows) => stage.rows += rows,
+            ProgressUpdate::BatchProcessed(messages) => {
+                stage.rows += messages;
+                stage.batches += 1;
             }
+            ProgressUpdate::StreamFinished => stage.finished = true,
+        }
+        self.changed.insert(event.stage);
+    }
+
+    /// Returns the stages that changed since the last call, sorted by name
+    fn take_changes(&mut self) -> Vec<Progress> {
+        std::mem::take(&mut self.changed)
+            .into_iter()
+            .map(|stage| {
+                let progress = self.stages[&stage].clone();
+                Progress::Stage { stage, progress }
+            })
+            .collect()
+    }
+}
+
+/// Plain bytes of an upload, failing with `FileTooLarge` once they'd exceed their budget
+#[derive(Default)]
+struct PlainBytes {
+    bytes: Vec<u8>,
+    budget: u64,
+}
+
+impl Write for PlainBytes {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.budget {
+            return Err(Error::new(ErrorKind::FileTooLarge, "Decoded bytes exceed the budget"));
         }
-        return Ok(received);
+        self.budget -= buf.len() as u64;
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Turns upload chunks back into the plain file bytes
+enum UploadDecoder {
+    Plain,
+    Gzip(Box<GzDecoder<PlainBytes>>),
+}
+
+impl UploadDecoder {
+    fn new(compression: Compression) -> Self {
+        match compression {
+            Compression::None => UploadDecoder::Plain,
+            Compression::Gzip => UploadDecoder::Gzip(Box::new(GzDecoder::new(PlainBytes::default()))),
+        }
+    }
+
+    /// Returns the plain bytes decoded so far. An empty chunk ends the file. Compressed chunks
+    /// are inflated a buffer at a time, failing with `FileTooLarge` as soon as they exceed
+    /// `budget` bytes
+    fn decode(&mut self, chunk: &[u8], budget: u64) -> io::Result<Vec<u8>> {
+        match self {
+            UploadDecoder::Plain => {
+                let mut plain = PlainBytes { bytes: Vec::new(), budget };
+                plain.write_all(chunk)?;
+                Ok(plain.bytes)
+            }
+            UploadDecoder::Gzip(decoder) => {
+                decoder.get_mut().budget = budget;
+                if chunk.is_empty() {
+                    decoder.try_finish()?;
+                } else {
+                    decoder.write_all(chunk)?;
+                }
+                Ok(std::mem::take(&mut decoder.get_mut().bytes))
+            }
+        }
+    }
+}
+
+/// Consumes the results queue and forwards each result to the client session it belongs to.
+/// Progress events are forwarded the same way, as `Message::Progress`
+#[derive(Clone, Default)]
+struct ResultsRouter {
+    sessions: Arc<Mutex<HashMap<u64, Sender<Message>>>>,
+}
+
+impl ResultsRouter {
+    fn register(&self, session_id: u64) -> Receiver<Message> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.sessions.lock().unwrap().insert(session_id, sender);
+        receiver
     }
 
-    fn wait_for_results(&self, config: &Config) -> Result<Results> {
+    fn unregister(&self, session_id: u64) {
+        self.sessions.lock().unwrap().remove(&session_id);
+    }
+
+    fn connect(config: &Config) -> Result<Connection> {
         let host_addr = format!(
             "amqp://{}:{}@{}:{}",
             config.user, config.pass, config.server_host, config.server_port
         );
         debug!("Connecting to: {}", host_addr);
+        Connection::insecure_open(&host_addr)
+    }
 
-        let mut connection = Connection::insecure_open(&host_addr)?;
+    fn run(&self, config: &Config) -> Result<()> {
+        let mut connection = Self::connect(config)?;
         let channel = connection.open_channel(None)?;
 
         let options = QueueDeclareOptions {
@@ -213,102 +919,100 @@ impl Server {
         };
         let queue = channel.queue_declare(RESULTS_QUEUE_NAME, options)?;
 
-        // Query results
-        let mut results = Results::default();
-        let mut data_received = (false, false, false);
         let consumer = queue.consume(ConsumerOptions::default())?;
         let consumer = DeliveryConsumer::new(consumer);
         let buf_consumer = BufConsumer::new(consumer);
-        info!("Starting iteration");
         for compound_delivery in buf_consumer {
-            for message in compound_delivery.data {
-                match message {
-                    Message::PostScoreMean(mean) => {
-                        info!("got mean: {:?}", mean);
-                        results.score_mean = mean;
-                        data_received.0 = true;
-                    }
-                    Message::PostUrl(id, url) => {
-                        info!("got best meme url: {:?}, {}", url, id);
-                        results.best_meme = url;
-                        data_received.1 = true;
-                    }
-                    Message::CollegePostUrl(url) => {
-                        results.college_posts.push(url);
-                    }
-                    Message::EndOfStream => {}
-                    Message::CollegePostEnded => {
-                        info!("College posts ended");
-                        data_received.2 = true;
-                    }
-                    Message::Confirmed  => {}
-                    _ => {
-                        error!("Invalid message arrived {:?}", message);
+            let session_id = compound_delivery.session_id;
+            match self.sessions.lock().unwrap().get(&session_id) {
+                Some(sender) => {
+                    for message in compound_delivery.data {
+                        let _ = sender.send(message);
                     }
                 }
+                None => warn!("Dropping results of unknown session {}", session_id),
             }
             compound_delivery.msg_delivery.ack(&channel)?;
             compound_delivery.confirm_delivery.ack(&channel)?;
-            if data_received.0 && data_received.1 && data_received.2 {
-                break;
-            }
         }
-        info!("Exit");
         let _ = connection.close();
-        Ok(results)
-    }
-
-    fn send_results_to_client(&self, stream: &mut TcpStream, results: &Results) -> io::Result<()>{
-        let best_meme = results.best_meme.as_bytes();
-        let best_meme_len = best_meme.len() as u64;
-        stream.write_all(&best_meme_len.to_be_bytes())?;
-        stream.write_all(best_meme)?;
-        let score_mean_string = results.score_mean.to_string();
-        let score_mean_bytes = score_mean_string.as_bytes();
-        let score_mean_len = score_mean_bytes.len() as u64;
-        stream.write_all(&score_mean_len.to_be_bytes())?;
-        stream.write_all(score_mean_bytes)?;
-        for college_post in results.college_posts.clone() {
-            let college_post_bytes = college_post.as_bytes();
-            let college_post_len = college_post_bytes.len() as u64;
-            stream.write_all(&college_post_len.to_be_bytes())?;
-            stream.write_all(college_post_bytes)?;
-        }
-        let ending_msg = "FINISHED";
-        let ending_msg_bytes = ending_msg.as_bytes() ;
-        let ending_msg_bytes_len = ending_msg.len() as u64;
-        stream.write_all(&ending_msg_bytes_len.to_be_bytes())?;
-        stream.write_all(ending_msg_bytes)?;
         Ok(())
     }
 
-    fn answer_not_available(&self, stream: &mut TcpStream) {
-        let not_available = "Server not available";
-        let not_available_bytes = not_available.as_bytes() ;
-        let not_available_bytes_len = not_available.len() as u64;
-        let _ = stream.write_all(&not_available_bytes_len.to_be_bytes());
-        let _ = stream.write_all(not_available_bytes);
-        println!("Sent not available to client");
-    }
+    /// Consumes the progress events published by every stage. They are informative only,
+    /// so they aren't acked and events of sessions no longer waiting are dropped
+    fn run_progress(&self, config: &Config) -> Result<()> {
+        let mut connection = Self::connect(config)?;
+        let channel = connection.open_channel(None)?;
 
+        let exchange_options = ExchangeDeclareOptions {
+            durable: true,
+            ..ExchangeDeclareOptions::default()
+        };
+        let exchange = channel.exchange_declare(ExchangeType::Fanout, PROGRESS_EXCHANGE_NAME, exchange_options)?;
+        let queue = channel.queue_declare(PROGRESS_QUEUE_NAME, QueueDeclareOptions::default())?;
+        queue.bind(&exchange, "", FieldTable::default())?;
+
+        let consumer = queue.consume(ConsumerOptions { no_ack: true, ..ConsumerOptions::default() })?;
+        for message in consumer.receiver() {
+            let delivery = match message {
+                ConsumerMessage::Delivery(delivery) => delivery,
+                other => {
+                    info!("Progress consumer ended: {:?}", other);
+                    break;
+                }
+            };
+            let event: ProgressEvent = match bincode::deserialize(&delivery.body) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Invalid progress event: {:?}", e);
+                    continue;
+                }
+            };
+            if let Some(sender) = self.sessions.lock().unwrap().get(&event.session_id) {
+                let _ = sender.send(Message::Progress(event));
+            }
+        }
+        let _ = connection.close();
+        Ok(())
+    }
 }
 
 #[derive(Clone, Envconfig)]
 pub struct ServerConfig {
-    /// Configuration file with the run commands for every service
-    #[envconfig(from = "CHUNK_SIZE", default = "1000")]
-    pub chunk_size: u64,
     #[envconfig(from = "SERVER_ADDRESS", default = "0.0.0.0:9090")]
     pub server_address: String,
     #[envconfig(from = "POSTS_PRODUCER_ADDRESS", default = "")]
     pub posts_producer_address: String,
     #[envconfig(from = "COMMENTS_PRODUCER_ADDRESS", default = "")]
     pub comments_producer_address: String,
+    /// Clients processed by the pipeline at the same time
+    #[envconfig(from = "MAX_CONCURRENT_JOBS", default = "2")]
+    pub max_concurrent_jobs: usize,
+    /// Clients waiting for a free job before new ones are rejected as busy
+    #[envconfig(from = "MAX_QUEUED_CLIENTS", default = "8")]
+    pub max_queued_clients: usize,
+    /// Seconds an interrupted upload waits for its client to reconnect
+    #[envconfig(from = "RESUME_TIMEOUT_SEC", default = "60")]
+    pub resume_timeout_sec: u64,
+    /// Seconds a job may spend processing once uploaded
+    #[envconfig(from = "JOB_DEADLINE_SEC", default = "600")]
+    pub job_deadline_sec: u64,
+    /// File where the job table is persisted
+    #[envconfig(from = "JOBS_PATH", default = "jobs.json")]
+    pub jobs_path: String,
+    /// Seconds finished, failed and cancelled jobs are kept for clients to fetch
+    #[envconfig(from = "JOB_RETENTION_SEC", default = "604800")]
+    pub job_retention_sec: u64,
+    /// Bytes accepted for each uploaded file
+    #[envconfig(from = "MAX_UPLOAD_SIZE", default = "4294967296")]
+    pub max_upload_size: u64,
+    /// Bytes accepted in a single upload chunk
+    #[envconfig(from = "MAX_CHUNK_SIZE", default = "1048576")]
+    pub max_chunk_size: usize,
 }
 
-#[derive(Debug, Default)]
-struct Results {
-    best_meme: String,
-    score_mean: f32,
-    college_posts: Vec<String>,
+/// Suffix of the lines printed about a group of results
+fn group_label(group: &str) -> String {
+    if group.is_empty() { String::new() } else { format!(" for subreddit {}", group) }
 }
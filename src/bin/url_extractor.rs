@@ -5,7 +5,7 @@ use tp2::middleware::message_processor::MessageProcessor;
 use tp2::middleware::service::{init, RabbitService};
 use tp2::middleware::RabbitExchange;
 use tp2::{
-    Config, POST_EXTRACTED_URL_QUEUE_NAME, POST_ID_WITH_URL_QUEUE_NAME, POST_URL_QUEUE_NAME,
+    Config, POST_EXTRACTED_URL_QUEUE_NAME, POST_ID_SENTIMENT_QUEUE_NAME, POST_URL_QUEUE_NAME,
 };
 use tp2::health_checker::health_answerer::HealthAnswerer;
 use std::sync::Arc;
@@ -32,6 +32,7 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+#[derive(Clone)]
 struct UrlExtractor;
 
 impl MessageProcessor for UrlExtractor {
@@ -40,7 +41,7 @@ impl MessageProcessor for UrlExtractor {
         match message {
             Message::FullPost(post) => {
                 if post.url.starts_with("http") {
-                    return Some(Message::PostUrl(post.id, post.url));
+                    return Some(Message::PostUrl(post.group, post.id, post.url));
                 }
             }
             _ => {
@@ -56,14 +57,14 @@ impl MessageProcessor for UrlExtractor {
         message: Message,
     ) -> Result<()> {
         exchange.send_with_key(&message, POST_EXTRACTED_URL_QUEUE_NAME)?;
-        exchange.send_with_key(&Message::Confirmed, POST_EXTRACTED_URL_QUEUE_NAME)?;
-        exchange.send_with_key(&message, POST_ID_WITH_URL_QUEUE_NAME)?;
-        exchange.send_with_key(&Message::Confirmed, POST_ID_WITH_URL_QUEUE_NAME)
+        exchange.send_with_key(&message.confirm(), POST_EXTRACTED_URL_QUEUE_NAME)?;
+        exchange.send_with_key(&message, POST_ID_SENTIMENT_QUEUE_NAME)?;
+        exchange.send_with_key(&message.confirm(), POST_ID_SENTIMENT_QUEUE_NAME)
     }
 }
 
 fn run_service(config: Config) -> Result<()> {
-    let mut processor = UrlExtractor;
-    let mut service = RabbitService::new(config, &mut processor);
+    let processor = UrlExtractor;
+    let mut service = RabbitService::new(config, processor);
     service.run(POST_URL_QUEUE_NAME, None)
 }
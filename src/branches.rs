@@ -0,0 +1,67 @@
+//! Branches of the pipeline fed by the source exchanges. Source exchanges are topic
+//! exchanges: each branch queue is bound with `#.{branch}.#` and producers publish with the
+//! branches a job needs joined by dots, so a single publish reaches every one of them.
+//!
+//! The score extractor can run as several replicas. Each one has its own queue, bound with
+//! `#.score_{replica}.#`, as competing consumers would split the pairs of bulks and confirms.
+
+use crate::protocol::{FileKind, Query};
+
+/// Feeds score extractor, for the score mean and the college posts above it
+pub const SCORE_BRANCH: &str = "score";
+/// Feeds post average filter with posts and comment college filter with comments
+pub const COLLEGE_BRANCH: &str = "college";
+/// Feeds url extractor, for the best meme
+pub const URL_BRANCH: &str = "url";
+/// Feeds comment sentiment extractor, for the best meme
+pub const SENTIMENT_BRANCH: &str = "sentiment";
+
+fn branches(file: FileKind, query: Query) -> &'static [&'static str] {
+    match (file, query) {
+        (FileKind::Posts, Query::ScoreMean) => &[SCORE_BRANCH],
+        (FileKind::Posts, Query::BestMeme) => &[URL_BRANCH],
+        (FileKind::Posts, Query::CollegePosts) => &[SCORE_BRANCH, COLLEGE_BRANCH],
+        (FileKind::Comments, Query::ScoreMean) => &[],
+        (FileKind::Comments, Query::BestMeme) => &[SENTIMENT_BRANCH],
+        (FileKind::Comments, Query::CollegePosts) => &[COLLEGE_BRANCH],
+    }
+}
+
+/// Word of the score branch fed to the score extractor replica `replica`
+pub fn score_branch(replica: usize) -> String {
+    format!("{}_{}", SCORE_BRANCH, replica)
+}
+
+/// Routing keys reaching the branches of `file` needed by `queries`, one per score extractor
+/// replica if the score branch is needed, for producers to spread their bulks among them. Empty
+/// if no branch is needed
+pub fn routing_keys(file: FileKind, queries: &[Query], score_replicas: usize) -> Vec<String> {
+    let mut needed: Vec<&str> = queries
+        .iter()
+        .flat_map(|query| branches(file, *query).iter().copied())
+        .collect();
+    needed.sort_unstable();
+    needed.dedup();
+    if needed.is_empty() {
+        return vec![];
+    }
+    if !needed.contains(&SCORE_BRANCH) {
+        return vec![needed.join(".")];
+    }
+    (0..score_replicas.max(1))
+        .map(|replica| {
+            let words: Vec<String> = needed
+                .iter()
+                .map(|branch| if *branch == SCORE_BRANCH { score_branch(replica) } else { branch.to_string() })
+                .collect();
+            words.join(".")
+        })
+        .collect()
+}
+
+/// Routing keys of the end of stream markers of `file`: one reaching every branch, so branches a
+/// job skipped still close its session, and one for each other score extractor replica
+pub fn end_of_stream_keys(file: FileKind, score_replicas: usize) -> Vec<String> {
+    let all_branches = routing_keys(file, &Query::ALL, score_replicas);
+    all_branches.iter().take(1).cloned().chain((1..all_branches.len()).map(score_branch)).collect()
+}
@@ -1,88 +1,134 @@
-use csv::{Reader, ReaderBuilder, StringRecord};
+use crate::keywords::KeywordMatcher;
+use crate::protocol::{GroupBy, RejectReason, NO_SUBREDDIT};
+use crate::rows::RecordIterator;
+use crate::schema::{self, ColumnMap};
+use csv::StringRecord;
 use lazy_static::lazy_static;
 use log::debug;
 use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
-use std::net::TcpStream;
 
-const COLLEGE_WORDS: &str = "university|college|student|teacher|professor";
-const URL_PATTERN: &str = "https://old.reddit.com/r/meirl/comments/([^/]+)/meirl/.*";
+/// Permalink of a comment in any subreddit, either absolute on any reddit host or relative
+const URL_PATTERN: &str = r"^(?:https?://(?:[\w-]+\.)?reddit\.com)?/r/[^/]+/comments/([[:alnum:]]+)(?:/|$)";
+/// Prefix of post ids in link ids
+const POST_ID_PREFIX: &str = "t3_";
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Comment {
     id: String,
     subreddit_id: String,
     nsfw: bool,
-    created: u64,
+    /// Unix timestamp
+    pub created: Option<u64>,
     permalink: String,
     body: String,
-    pub sentiment: String,
-    score: String,
+    pub sentiment: Option<f32>,
+    pub score: Option<i64>,
+    /// Id of the post, if the file had a link id column
+    #[serde(default)]
+    link_id: Option<String>,
+    /// Results the comment counts towards: its subreddit id if the session is grouped by
+    /// subreddit, empty otherwise
+    #[serde(default)]
+    pub group: String,
 }
 
 impl Comment {
-    fn from(record: StringRecord) -> Option<Self> {
+    pub fn parse(record: &StringRecord, columns: &ColumnMap) -> Result<Self, RejectReason> {
         debug!("Record: {:?}", record);
-        Some(Self {
-            id: record.get(1)?.to_string(),
-            subreddit_id: record.get(2)?.to_string(),
-            nsfw: record.get(4)?.eq("true"),
-            created: str::parse::<u64>(record.get(5)?).expect("Created timestamp is invalid"),
-            permalink: record.get(6)?.to_string(),
-            body: record.get(7)?.to_string(),
-            sentiment: record.get(8)?.to_string(),
-            //score: str::parse::<u32>(record.get(9)?).expect("Score is invalid"),
-            score: record.get(9)?.to_string(),
+        Ok(Self {
+            id: columns.value(record, schema::ID)?.to_string(),
+            subreddit_id: columns.value(record, schema::SUBREDDIT_ID)?.to_string(),
+            nsfw: columns.value(record, schema::NSFW)?.eq("true"),
+            created: columns.parse_optional(record, schema::CREATED)?,
+            permalink: columns.value(record, schema::PERMALINK)?.to_string(),
+            body: columns.value(record, schema::BODY)?.to_string(),
+            sentiment: columns.parse_optional(record, schema::SENTIMENT)?,
+            score: columns.parse_optional(record, schema::SCORE)?,
+            link_id: columns
+                .get(record, schema::LINK_ID)
+                .filter(|link_id| !link_id.is_empty())
+                .map(str::to_string),
+            group: String::new(),
         })
     }
 
+    pub fn set_group(&mut self, group_by: GroupBy) {
+        self.group = match group_by {
+            GroupBy::None => String::new(),
+            GroupBy::Subreddit if self.subreddit_id.is_empty() => NO_SUBREDDIT.to_string(),
+            GroupBy::Subreddit => self.subreddit_id.clone(),
+        };
+    }
+
+    /// Id of the post the comment belongs to, from its link id or else its permalink. Link ids
+    /// of other kinds, like parent comments, are ignored
     pub fn parse_post_id(&self) -> Option<String> {
         lazy_static! {
             /// Avoid compiling the same regex everytime
-            static ref URL_REGEX: Regex = Regex::new(URL_PATTERN).unwrap();
+            static ref URL_REGEX: Regex = RegexBuilder::new(URL_PATTERN).case_insensitive(true).build().unwrap();
+        }
+        if let Some(post_id) = self.link_id.as_deref().and_then(post_id_from_link) {
+            return Some(post_id.to_string());
         }
         let matched = URL_REGEX.captures(&self.permalink)?.get(1)?;
         Some(matched.as_str().to_string())
     }
 
-    pub fn is_college_related(&self) -> bool {
-        lazy_static! {
-            /// Avoid compiling the same regex everytime
-            static ref COLLEGE_RELATED_REGEX: Regex = RegexBuilder::new(COLLEGE_WORDS)
-                .case_insensitive(true)
-                .build()
-                .expect("Invalid Regex");
-        }
-        COLLEGE_RELATED_REGEX.is_match(&self.body)
+    /// College keywords found in the body, empty if it isn't college related
+    pub fn college_keywords<'k>(&self, keywords: &'k KeywordMatcher) -> Vec<&'k str> {
+        keywords.matches(&self.body)
     }
 }
 
-pub struct CommentIterator {
-    reader: Reader<TcpStream>,
+/// Post id in a link id, bare or `t3_` prefixed. Ids of other kinds, like `t1_` comments, have none
+fn post_id_from_link(link_id: &str) -> Option<&str> {
+    let link_id = link_id.trim();
+    match link_id.strip_prefix(POST_ID_PREFIX) {
+        Some(post_id) => Some(post_id),
+        None if link_id.contains('_') => None,
+        None => Some(link_id),
+    }
 }
 
-impl CommentIterator {
-    // pub fn from_file(path: &str) -> Self {
-    //     let comment_file = File::open(path).unwrap();
-    //     let reader = ReaderBuilder::new()
-    //         .has_headers(true)
-    //         .from_reader(comment_file);
-    //     Self { reader }
-    // }
-    pub fn from_stream(stream: TcpStream) -> Self {
-        let reader = ReaderBuilder::new()
-            .has_headers(true)
-            .from_reader(stream);
-        Self { reader }
+/// Reads comments from any source: a client stream, a local file, stdin or a buffer
+pub type CommentIterator<R> = RecordIterator<R, Comment>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post_id(permalink: &str, link_id: Option<&str>) -> Option<String> {
+        let comment = Comment {
+            permalink: permalink.to_string(),
+            link_id: link_id.map(str::to_string),
+            ..Default::default()
+        };
+        comment.parse_post_id()
     }
-}
 
-impl Iterator for CommentIterator {
-    type Item = Comment;
+    #[test]
+    fn post_id_is_read_from_permalinks() {
+        assert_eq!(post_id("https://old.reddit.com/r/AskReddit/comments/qbn1ll/title/hhb7wsm/", None).as_deref(), Some("qbn1ll"));
+        assert_eq!(post_id("HTTP://WWW.Reddit.com/r/a/comments/ab12/", None).as_deref(), Some("ab12"));
+        assert_eq!(post_id("https://reddit.com/r/a/comments/ab12", None).as_deref(), Some("ab12"));
+        assert_eq!(post_id("/r/a/comments/ab12/title/", None).as_deref(), Some("ab12"));
+    }
+
+    #[test]
+    fn other_urls_have_no_post_id() {
+        assert_eq!(post_id("https://example.com/r/a/comments/ab12/", None), None);
+        assert_eq!(post_id("https://old.reddit.com/r/a/about/", None), None);
+        assert_eq!(post_id("https://old.reddit.com/r/a/comments/ab-12/", None), None);
+        assert_eq!(post_id("see /r/a/comments/ab12/", None), None);
+        assert_eq!(post_id("", None), None);
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let record_res = self.reader.records().next()?;
-        let record = record_res.ok()?;
-        Comment::from(record)
+    #[test]
+    fn link_ids_take_precedence_over_permalinks() {
+        let permalink = "/r/a/comments/fromurl/";
+        assert_eq!(post_id(permalink, Some("t3_fromlink")).as_deref(), Some("fromlink"));
+        assert_eq!(post_id(permalink, Some(" fromlink ")).as_deref(), Some("fromlink"));
+        assert_eq!(post_id(permalink, Some("t1_parent")).as_deref(), Some("fromurl"));
     }
 }
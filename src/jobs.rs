@@ -0,0 +1,297 @@
+use crate::output;
+use crate::protocol::{GroupBy, JobStatus, Query, RankedMeme, RejectedRows, ScoreSummary};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QueryResults {
+    pub best_memes: Option<Vec<RankedMeme>>,
+    pub score_stats: Option<ScoreSummary>,
+    pub college_posts: Option<Vec<String>>,
+}
+
+impl QueryResults {
+    pub fn is_empty(&self) -> bool {
+        self.best_memes.is_none() && self.score_stats.is_none() && self.college_posts.is_none()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct JobResults {
+    /// Results of jobs that aren't grouped
+    #[serde(flatten)]
+    pub global: QueryResults,
+    #[serde(default)]
+    pub group_by: GroupBy,
+    /// Results of each subreddit, for jobs grouped by subreddit
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub subreddits: BTreeMap<String, QueryResults>,
+    /// Queries that didn't finish before the job deadline
+    #[serde(default)]
+    pub missing: Vec<Query>,
+    /// Rows of the uploaded files left out of the results
+    #[serde(default)]
+    pub rejected: Vec<RejectedRows>,
+    /// Comments that matched each college keyword, over the whole job
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub college_keyword_hits: BTreeMap<String, u64>,
+}
+
+impl JobResults {
+    /// Results of a group, as keyed by the pipeline: a subreddit id, or empty for the global
+    /// results. A subreddit marks the results as grouped by subreddit
+    pub fn group_mut(&mut self, group: &str) -> &mut QueryResults {
+        if group.is_empty() {
+            &mut self.global
+        } else {
+            self.group_by = GroupBy::Subreddit;
+            self.subreddits.entry(group.to_string()).or_default()
+        }
+    }
+
+    /// Results paired with the subreddit they belong to, empty for the global results. Grouped
+    /// jobs only have global results when a query had no rows to group, like a job without posts
+    pub fn groups(&self) -> Vec<(&str, &QueryResults)> {
+        let global = ("", &self.global);
+        match self.group_by {
+            GroupBy::None => vec![global],
+            GroupBy::Subreddit => (!self.global.is_empty())
+                .then_some(global)
+                .into_iter()
+                .chain(self.subreddits.iter().map(|(group, results)| (group.as_str(), results)))
+                .collect(),
+        }
+    }
+}
+
+/// How far the server got with a job, journaled to recover it after a restart
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobPhase {
+    /// No producer got the session yet
+    Started,
+    /// The posts producer got the session, the comments producer didn't
+    ForwardingPosts,
+    /// Both producers got the session
+    ForwardingComments,
+    /// Both files were forwarded, results are on their way
+    AwaitingResults,
+    /// The job was aborted before the comments producer got the session. The pipeline can't
+    /// close the session until it gets its comments EOS
+    CommentsEosPending,
+}
+
+impl JobPhase {
+    /// Phase left once a job in this phase is aborted
+    pub fn aborted(self) -> Option<JobPhase> {
+        match self {
+            JobPhase::ForwardingPosts | JobPhase::CommentsEosPending => Some(JobPhase::CommentsEosPending),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub status: JobStatus,
+    /// Only read from tables saved before results had their own files, to move them there
+    #[serde(default, skip_serializing)]
+    results: Option<JobResults>,
+    #[serde(default)]
+    pub queries: Vec<Query>,
+    #[serde(default)]
+    pub group_by: GroupBy,
+    /// None once the job no longer needs the server
+    #[serde(default)]
+    pub phase: Option<JobPhase>,
+    /// Last change of the job, in seconds since the epoch
+    #[serde(default)]
+    updated_at: u64,
+}
+
+impl Job {
+    /// Whether the job is done with, so it can be evicted once past the retention
+    fn is_over(&self) -> bool {
+        self.phase.is_none() && !matches!(self.status, JobStatus::Uploading | JobStatus::Processing)
+    }
+}
+
+/// Jobs known by the server, saved to disk on every change so clients can poll them and the
+/// server can recover them after a restart. Results are saved to a file of their own per job,
+/// `{path}.{job_id}.results`, so changes to the table don't rewrite them
+pub struct JobTable {
+    path: String,
+    jobs: Mutex<HashMap<u64, Job>>,
+    /// Time jobs are kept once over, for clients to fetch their results
+    retention: Duration,
+}
+
+impl JobTable {
+    /// Loads the table saved at `path`. Jobs awaiting results are kept for the server to resume
+    /// them, jobs that were uploading are marked as failed. A table that can't be read is moved
+    /// aside rather than overwritten, so its jobs can still be recovered by hand
+    pub fn load(path: &str, retention: Duration) -> Self {
+        let loaded = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| serde_json::from_str::<HashMap<u64, Job>>(&text).map_err(|e| e.to_string()));
+        let mut jobs = match loaded {
+            Ok(jobs) => jobs,
+            Err(_) if !Path::new(path).exists() => HashMap::new(),
+            Err(e) => {
+                let backup = format!("{}.{}.bad", path, now());
+                error!("Can't load the job table {}, moving it to {}: {}", path, backup, e);
+                std::fs::rename(path, &backup).expect("Failed to move the job table aside");
+                HashMap::new()
+            }
+        };
+        let table = Self {
+            path: path.to_string(),
+            jobs: Mutex::new(HashMap::new()),
+            retention,
+        };
+        for (job_id, job) in jobs.iter_mut() {
+            if let Some(results) = job.results.take() {
+                table.save_results(*job_id, &results);
+            }
+            if job.updated_at == 0 {
+                job.updated_at = now();
+            }
+            if job.phase == Some(JobPhase::AwaitingResults) {
+                continue;
+            }
+            if matches!(job.status, JobStatus::Uploading | JobStatus::Processing) {
+                job.status = JobStatus::Failed {
+                    reason: "Server restarted while the job was running".to_string(),
+                };
+            }
+            job.phase = job.phase.and_then(JobPhase::aborted);
+        }
+        table.expire(&mut jobs);
+        table.save(&jobs);
+        *table.jobs.lock().unwrap() = jobs;
+        table
+    }
+
+    pub fn get(&self, job_id: u64) -> Option<Job> {
+        self.jobs.lock().unwrap().get(&job_id).cloned()
+    }
+
+    /// Jobs left in `phase`, with the queries they asked for
+    pub fn with_phase(&self, phase: JobPhase) -> Vec<(u64, Vec<Query>)> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter()
+            .filter(|(_, job)| job.phase == Some(phase))
+            .map(|(job_id, job)| (*job_id, job.queries.clone()))
+            .collect()
+    }
+
+    pub fn start(&self, job_id: u64, queries: &[Query], group_by: GroupBy) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = Job {
+            status: JobStatus::Uploading,
+            results: None,
+            queries: queries.to_vec(),
+            group_by,
+            phase: Some(JobPhase::Started),
+            updated_at: now(),
+        };
+        jobs.insert(job_id, job);
+        self.expire(&mut jobs);
+        self.save(&jobs);
+    }
+
+    /// Results of a finished job, None if it has none or they can't be read
+    pub fn results(&self, job_id: u64) -> Option<JobResults> {
+        let path = self.results_path(job_id);
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("Failed to read {}: {:?}", path, e);
+                }
+            })
+            .ok()?;
+        serde_json::from_str(&text).map_err(|e| error!("Failed to parse {}: {:?}", path, e)).ok()
+    }
+
+    pub fn set_status(&self, job_id: u64, status: JobStatus) {
+        self.update(job_id, |job| job.status = status);
+    }
+
+    pub fn set_phase(&self, job_id: u64, phase: Option<JobPhase>) {
+        self.update(job_id, |job| job.phase = phase);
+    }
+
+    /// Saves the results before marking the job as finished, so finished jobs always have them
+    pub fn finish(&self, job_id: u64, results: JobResults) {
+        self.save_results(job_id, &results);
+        self.update(job_id, |job| {
+            job.status = JobStatus::Finished;
+            job.phase = None;
+        });
+    }
+
+    fn update<F: FnOnce(&mut Job)>(&self, job_id: u64, update: F) {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get_mut(&job_id) {
+            Some(job) => {
+                update(job);
+                job.updated_at = now();
+            }
+            None => {
+                error!("Unknown job {}", job_id);
+                return;
+            }
+        }
+        self.save(&jobs);
+    }
+
+    /// Evicts the jobs over for longer than the retention, with their results
+    fn expire(&self, jobs: &mut HashMap<u64, Job>) {
+        let oldest = now().saturating_sub(self.retention.as_secs());
+        jobs.retain(|job_id, job| {
+            if !job.is_over() || job.updated_at >= oldest {
+                return true;
+            }
+            info!("Evicting job {}", job_id);
+            if let Err(e) = std::fs::remove_file(self.results_path(*job_id)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("Failed to delete the results of job {}: {:?}", job_id, e);
+                }
+            }
+            false
+        });
+    }
+
+    fn results_path(&self, job_id: u64) -> String {
+        format!("{}.{}.results", self.path, job_id)
+    }
+
+    /// Saves the results of a job. While it awaits the rest, `missing` lists the queries still
+    /// pending, so it can be resumed from them after a restart
+    pub fn save_results(&self, job_id: u64, results: &JobResults) {
+        let saved = serde_json::to_vec(results)
+            .map_err(io::Error::from)
+            .and_then(|json| output::write_atomically(&self.results_path(job_id), &json));
+        if let Err(e) = saved {
+            error!("Failed to save the results of job {}: {:?}", job_id, e);
+        }
+    }
+
+    fn save(&self, jobs: &HashMap<u64, Job>) {
+        if let Err(e) = self.write(jobs) {
+            error!("Failed to save job table: {:?}", e);
+        }
+    }
+
+    fn write(&self, jobs: &HashMap<u64, Job>) -> io::Result<()> {
+        output::write_atomically(&self.path, &serde_json::to_vec(jobs)?)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
@@ -0,0 +1,129 @@
+//! Keywords that make a comment college related. They match whole words, in any case and
+//! either singular or plural, so "students" matches "student" but "collegeville" doesn't match
+//! "college".
+
+use regex::{Regex, RegexBuilder};
+use std::fs;
+
+pub const DEFAULT_COLLEGE_KEYWORDS: &str = "university,college,student,teacher,professor";
+
+pub struct KeywordMatcher {
+    keywords: Vec<String>,
+    /// One capture group per keyword, in the same order
+    regex: Regex,
+}
+
+impl KeywordMatcher {
+    pub fn new(keywords: Vec<String>) -> Result<Self, String> {
+        if keywords.is_empty() {
+            return Err("No keywords to match".to_string());
+        }
+        let alternatives: Vec<String> = keywords.iter().map(|keyword| format!("({})", word_forms(keyword))).collect();
+        let regex = RegexBuilder::new(&format!(r"\b(?:{})\b", alternatives.join("|")))
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self { keywords, regex })
+    }
+
+    /// Keywords from `file`, one per line skipping blank lines and `#` comments, or from the
+    /// comma separated `list` if no file is given
+    pub fn load(list: &str, file: &str) -> Result<Self, String> {
+        let keywords: Vec<String> = if file.is_empty() {
+            list.split(',').map(str::to_string).collect()
+        } else {
+            let text = fs::read_to_string(file).map_err(|e| format!("Can't read {}: {}", file, e))?;
+            text.lines().filter(|line| !line.trim_start().starts_with('#')).map(str::to_string).collect()
+        };
+        let keywords = keywords
+            .into_iter()
+            .map(|keyword| keyword.trim().to_lowercase())
+            .filter(|keyword| !keyword.is_empty())
+            .collect();
+        Self::new(keywords)
+    }
+
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    /// Distinct keywords found in `text`, in the order they were configured
+    pub fn matches(&self, text: &str) -> Vec<&str> {
+        let mut found = vec![false; self.keywords.len()];
+        for captures in self.regex.captures_iter(text) {
+            if let Some(index) = (1..captures.len()).find(|group| captures.get(*group).is_some()) {
+                found[index - 1] = true;
+            }
+        }
+        self.keywords
+            .iter()
+            .zip(found)
+            .filter(|(_, found)| *found)
+            .map(|(keyword, _)| keyword.as_str())
+            .collect()
+    }
+}
+
+/// Pattern matching the singular and plural of an English word
+fn word_forms(keyword: &str) -> String {
+    let vowels = ['a', 'e', 'i', 'o', 'u'];
+    let mut chars = keyword.chars().rev();
+    let (last, before_last) = (chars.next(), chars.next());
+    match (last, before_last) {
+        (Some('y'), Some(previous)) if !vowels.contains(&previous) => {
+            format!("{}(?:y|ies)", regex::escape(&keyword[..keyword.len() - 1]))
+        }
+        (Some('s' | 'x' | 'z'), _) => format!("{}(?:es)?", regex::escape(keyword)),
+        (Some('h'), Some('c' | 's')) => format!("{}(?:es)?", regex::escape(keyword)),
+        _ => format!("{}s?", regex::escape(keyword)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(list: &str) -> KeywordMatcher {
+        KeywordMatcher::load(list, "").unwrap()
+    }
+
+    #[test]
+    fn matches_whole_words_in_any_case_and_number() {
+        let keywords = matcher(DEFAULT_COLLEGE_KEYWORDS);
+        assert_eq!(keywords.matches("Two STUDENTS and a Professor"), ["student", "professor"]);
+        assert_eq!(keywords.matches("Universities and colleges"), ["university", "college"]);
+        assert!(keywords.matches("collegeville studentship").is_empty());
+    }
+
+    #[test]
+    fn matches_are_distinct_in_configured_order() {
+        let keywords = matcher("teacher,college");
+        assert_eq!(keywords.matches("college, teacher, college"), ["teacher", "college"]);
+    }
+
+    #[test]
+    fn plurals_follow_english_rules() {
+        let keywords = matcher("class,church,day,box");
+        assert_eq!(keywords.matches("classes churches days boxes"), ["class", "church", "day", "box"]);
+        assert!(keywords.matches("dayies").is_empty());
+    }
+
+    #[test]
+    fn keywords_are_normalized_and_escaped() {
+        let keywords = matcher(" Ph.D , ,Dorm");
+        assert_eq!(keywords.keywords(), ["ph.d", "dorm"]);
+        assert_eq!(keywords.matches("ph.ds in dorms"), ["ph.d", "dorm"]);
+        assert!(keywords.matches("phxd").is_empty());
+        assert!(KeywordMatcher::load(" , ", "").is_err());
+    }
+
+    #[test]
+    fn keywords_are_loaded_from_a_file() {
+        let path = std::env::temp_dir().join(format!("keywords-{}.txt", std::process::id()));
+        fs::write(&path, "# college words\nCampus\n\n  tuition \n").unwrap();
+        let keywords = KeywordMatcher::load("ignored", path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(keywords.keywords(), ["campus", "tuition"]);
+        assert!(KeywordMatcher::load("", "/nonexistent/keywords.txt").is_err());
+    }
+}
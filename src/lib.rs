@@ -1,12 +1,22 @@
 use envconfig::Envconfig;
+use schema::ColumnAliases;
 use std::time::Duration;
 
+pub mod branches;
 pub mod comment;
 pub mod messages;
 pub mod middleware;
+pub mod output;
 pub mod post;
+pub mod protocol;
+pub mod rows;
+pub mod schema;
+pub mod stats;
 pub mod health_checker;
+pub mod jobs;
+pub mod keywords;
 pub mod task_manager;
+pub mod top_k;
 pub mod leader_election;
 pub mod sigterm_handler;
 
@@ -27,14 +37,24 @@ pub struct Config {
     /// RabbitMQ password
     #[envconfig(from = "RABBITMQ_PASS", default = "guest")]
     pub pass: String,
-    /// Number of producers sending data
+    /// Number of producers sending data. A session finishes after an EOS from each of them
     #[envconfig(from = "PRODUCERS", default = "1")]
     pub producers: String,
     /// Number of consumers reading data (in fanouts, use max number of each endpoint)
     #[envconfig(from = "CONSUMERS", default = "1")]
     pub consumers: String,
     #[envconfig(from = "TRANSACTION_LOG", default = "transaction.log")]
-    pub transaction_log_path: String
+    pub transaction_log_path: String,
+    /// Other header names of the uploaded CSV columns, as `column=alias|alias,...`. The Pushshift
+    /// field names are always accepted
+    #[envconfig(from = "COLUMN_ALIASES", default = "")]
+    pub column_aliases: ColumnAliases,
+    /// Memes ranked by the best meme query
+    #[envconfig(from = "BEST_MEMES", default = "3")]
+    pub best_memes: usize,
+    /// Posts ranked as best meme candidates, so the ones without a url can be passed over
+    #[envconfig(from = "BEST_MEME_CANDIDATES", default = "10")]
+    pub best_meme_candidates: usize,
 }
 
 /// Timeout for receive operations
@@ -42,37 +62,34 @@ pub const RECV_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Exchange with full posts
 pub const POSTS_SOURCE_EXCHANGE_NAME: &str = "tp2.posts";
-/// Exchange with full posts
+/// Exchange with full comments
 pub const COMMENTS_SOURCE_EXCHANGE_NAME: &str = "tp2.comments";
-/// Queue with full posts for mean score calculations
+/// Prefix of the queues with full posts for mean score calculations, one per score extractor
+/// replica, suffixed with its index
 pub const POST_SCORES_QUEUE_NAME: &str = "tp2.posts.score_src";
-/// Queue with full posts for college memes extraction
+/// Input of post average filter: full posts and the score mean
 pub const POST_COLLEGE_QUEUE_NAME: &str = "tp2.posts.college_src";
 /// Queue with full posts for college memes extraction
 pub const POST_URL_QUEUE_NAME: &str = "tp2.posts.url_src";
-/// Queue with posts urls with above average score
+/// Input of college posts filter: urls of posts with above average score and college post ids
 pub const POST_URL_AVERAGE_QUEUE_NAME: &str = "tp2.posts.above_average";
-/// Queue with posts urls for best meme
+/// Input of best meme filter: posts urls and the post with highest sentiment
 pub const POST_EXTRACTED_URL_QUEUE_NAME: &str = "tp2.posts.urls";
-/// Queue with ids of posts with urls
-pub const POST_ID_WITH_URL_QUEUE_NAME: &str = "tp2.posts.urls.id";
 /// Input for the mean calculator
 pub const POST_SCORE_MEAN_QUEUE_NAME: &str = "tp2.posts.mean";
-/// Input of college posts filter. Output of the mean calculator
-pub const POST_SCORE_AVERAGE_QUEUE_NAME: &str = "tp2.posts.mean.result";
 /// Input of comment sentiment extractor
 pub const COMMENT_SENTIMENT_QUEUE_NAME: &str = "tp2.comments.sentiment_src";
-/// Input of post sentiment filter. Output of comment sentiment extractor.
+/// Input of post sentiment filter. Output of comment sentiment extractor and url extractor.
 pub const POST_ID_SENTIMENT_QUEUE_NAME: &str = "tp2.posts.sentiment";
 /// Input of post sentiment calculator. Output of post sentiment filter.
 pub const FILTERED_POST_ID_SENTIMENT_QUEUE_NAME: &str = "tp2.posts.sentiment.filtered";
-/// Output of post sentiment calculator
-pub const POST_SENTIMENT_MEAN_QUEUE_NAME: &str = "tp2.posts.sentiment.best";
 /// Input of college comment filter
 pub const COMMENT_COLLEGE_QUEUE_NAME: &str = "tp2.comments.college_src";
-/// Input of college posts filter. Output of college comment filter.
-pub const POST_ID_COLLEGE_QUEUE_NAME: &str = "tp2.posts.college_id";
 /// Results queue
 pub const RESULTS_QUEUE_NAME: &str = "tp2.results";
+/// Exchange where nodes publish progress events
+pub const PROGRESS_EXCHANGE_NAME: &str = "tp2.progress";
+/// Queue with progress events for the server
+pub const PROGRESS_QUEUE_NAME: &str = "tp2.progress";
 /// Queue with data to save
 pub const DATA_TO_SAVE_QUEUE_NAME: &str = "tp2.data.save";
@@ -1,12 +1,10 @@
 use crate::comment::Comment;
 use crate::post::Post;
+use crate::protocol::{RankedMeme, RejectedRows, ScoreSummary};
+use crate::stats::ScoreStats;
 use serde::{Deserialize, Serialize};
-
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Score {
-    pub sum: u32,
-    pub count: u32,
-}
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PostSentiment {
@@ -21,31 +19,119 @@ pub struct BestMeme {
     pub url: String,
 }
 
+/// A post that may be a best meme
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MemeCandidate {
+    pub id: String,
+    /// Comments its mean sentiment comes from
+    pub comments: i32,
+}
+
+impl Ord for MemeCandidate {
+    /// Candidates that win a sentiment tie come first: the most commented, then the lowest id
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.comments.cmp(&self.comments).then_with(|| self.id.cmp(&other.id))
+    }
+}
+
+impl PartialOrd for MemeCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ProgressUpdate {
+    /// Rows read from the client upload since the last update
+    RowsIngested(u64),
+    /// A batch with this many messages was processed
+    BatchProcessed(u64),
+    StreamFinished,
+}
+
+/// Published by nodes to the progress exchange, outside of the confirmed message flow
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProgressEvent {
+    pub session_id: u64,
+    pub stage: String,
+    pub update: ProgressUpdate,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Message {
     StreamStart(u64),
     EndOfStream,
     FullPost(Post),
     FullComment(Comment),
-    PostScore(u32),
-    PostScoreMean(f32),
+    /// Stats of the post scores of a group in a delivery, merged by the mean calculator
+    PostScores(String, ScoreStats),
+    /// Score stats of each group
+    PostScoreStats(BTreeMap<String, ScoreSummary>),
     PostId(String),
-    PostUrl(String, String),
-    PostIdSentiment(String, f32),
-    CollegePostUrl(String),
+    /// Group, id and url of a post
+    PostUrl(String, String, String),
+    /// Group and id of a post, and the sentiment of one of its comments
+    PostIdSentiment(String, String, f32),
+    /// Best posts of each group with their mean sentiment, best first
+    BestMemeCandidates(BTreeMap<String, Vec<(MemeCandidate, f32)>>),
+    /// Best memes of each group, best first
+    BestMemes(BTreeMap<String, Vec<RankedMeme>>),
+    /// Group and url of a college related post
+    CollegePostUrl(String, String),
     CollegePostEnded,
+    /// Rows of the upload skipped by a producer, sent to the results queue before its EOS
+    RowsRejected(Vec<RejectedRows>),
     DataToSave(String, String),
-    BulkMessage(Vec<u8>, Vec<usize>),
-    Confirmed
+    /// Batch of serialized messages belonging to a single client session: the session id, the
+    /// bulk id, the messages and their sizes
+    BulkMessage(u64, u64, Vec<u8>, Vec<usize>),
+    /// Follows the bulk with this id once it was sent
+    Confirmed(u64),
+    Progress(ProgressEvent),
+    /// Comments of the session that matched each college keyword, sent with its college posts
+    CollegeKeywordHits(BTreeMap<String, u64>),
+}
+
+impl Message {
+    /// Id of a bulk, 0 for other messages
+    pub fn bulk_id(&self) -> u64 {
+        match self {
+            Message::BulkMessage(_, bulk_id, _, _) => *bulk_id,
+            _ => 0,
+        }
+    }
+
+    /// Confirm sent after a bulk. Consumers pair them by the bulk id, as other publishers of
+    /// the same queue may send theirs in between
+    pub fn confirm(&self) -> Message {
+        Message::Confirmed(self.bulk_id())
+    }
 }
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq)]
 pub struct BulkBuilder {
+    session_id: u64,
+    /// Random, so bulks of different publishers can't be mistaken. Kept in the transaction log
+    /// with the output, so a resent bulk keeps its id and consumers drop the repeated copy
+    #[serde(default)]
+    bulk_id: u64,
     data_buf: Vec<u8>,
     data_sizes: Vec<usize>,
 }
 
 impl BulkBuilder {
+    pub fn new(session_id: u64) -> Self {
+        Self {
+            session_id,
+            bulk_id: rand::random(),
+            ..Self::default()
+        }
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
     pub fn push<T: Serialize + std::fmt::Debug>(&mut self, message: &T) {
         let mut data = bincode::serialize(&message).unwrap();
         self.data_sizes.push(data.len());
@@ -55,7 +141,8 @@ impl BulkBuilder {
     pub fn build(&mut self) -> Message {
         let data = self.data_buf.drain(..).collect();
         let sizes = self.data_sizes.drain(..).collect();
-        Message::BulkMessage(data, sizes)
+        let bulk_id = std::mem::replace(&mut self.bulk_id, rand::random());
+        Message::BulkMessage(self.session_id, bulk_id, data, sizes)
     }
 
     pub fn size(&self) -> usize {
@@ -1,38 +1,69 @@
 use crate::messages::Message;
 use crate::middleware::consumer::DeliveryConsumer;
-use amiquip::Delivery;
+use amiquip::{Channel, Delivery, Result};
 use log::warn;
+use std::collections::HashMap;
 
 pub struct BufConsumer<'a> {
     consumer: DeliveryConsumer<'a>,
+    /// Bulks waiting for their confirm, by bulk id. Publishers sharing the queue interleave
+    /// their bulks and confirms, so each confirm is paired with its bulk by id
+    pending: HashMap<u64, (Delivery, Message)>,
 }
 
 pub struct CompoundDelivery {
+    /// Client session the messages belong to
+    pub session_id: u64,
     pub data: Vec<Message>,
     pub msg_delivery: Delivery,
     pub confirm_delivery: Delivery,
 }
 
+impl CompoundDelivery {
+    /// ACKs the bulk and its confirm. Other deliveries may still be pending, so they can't be
+    /// acked as multiple
+    pub fn ack(self, channel: &Channel) -> Result<()> {
+        self.msg_delivery.ack(channel)?;
+        self.confirm_delivery.ack(channel)
+    }
+}
+
 impl<'a> BufConsumer<'a> {
     pub fn new(consumer: DeliveryConsumer<'a>) -> Self {
-        Self { consumer }
+        Self { consumer, pending: HashMap::new() }
     }
 
     fn recv_messages(&mut self) -> Option<CompoundDelivery> {
-        let mut msg_delivery = self.consumer.next()?;
-        let mut confirm_delivery = self.consumer.next()?;
-        let mut message = bincode::deserialize::<Message>(&msg_delivery.body).unwrap();
-        let mut confirm_message = bincode::deserialize::<Message>(&confirm_delivery.body).unwrap();
-        while ! matches!(confirm_message, Message::Confirmed) || matches!(message, Message::Confirmed) {
-            warn!("Second message is not a confirm, must be a repeated msg");
-            self.consumer.ack(msg_delivery).ok()?;
-            msg_delivery = confirm_delivery;
-            confirm_delivery = self.consumer.next()?;
-            message = confirm_message;
-            confirm_message = bincode::deserialize::<Message>(&confirm_delivery.body).unwrap();
+        loop {
+            let delivery = self.consumer.next()?;
+            match bincode::deserialize::<Message>(&delivery.body).unwrap() {
+                Message::Confirmed(bulk_id) => match self.pending.remove(&bulk_id) {
+                    Some((msg_delivery, message)) => return Some(Self::unbulk(message, msg_delivery, delivery)),
+                    None => {
+                        warn!("Confirm of bulk {} without its bulk, must be a repeated confirm", bulk_id);
+                        self.consumer.ack(delivery).ok()?;
+                    }
+                },
+                message @ Message::BulkMessage(..) => {
+                    let bulk_id = message.bulk_id();
+                    if let Some((repeated, _)) = self.pending.insert(bulk_id, (delivery, message)) {
+                        warn!("Bulk {} arrived twice, must be a repeated msg", bulk_id);
+                        self.consumer.ack(repeated).ok()?;
+                    }
+                }
+                message => {
+                    warn!("Dropping unbatched message {:?}", message);
+                    self.consumer.ack(delivery).ok()?;
+                }
+            }
         }
+    }
+
+    fn unbulk(message: Message, msg_delivery: Delivery, confirm_delivery: Delivery) -> CompoundDelivery {
         let mut messages = Vec::new();
-        if let Message::BulkMessage(bulk, messages_sizes) = message {
+        let mut session_id = 0;
+        if let Message::BulkMessage(bulk_session_id, _, bulk, messages_sizes) = message {
+            session_id = bulk_session_id;
             let mut offset = 0;
             for i in messages_sizes {
                 let bytes = &bulk[offset..offset + i];
@@ -40,10 +71,8 @@ impl<'a> BufConsumer<'a> {
                 messages.push(msg);
                 offset += i;
             }
-        } else {
-            messages.push(message);
         }
-        Some(CompoundDelivery{data: messages, msg_delivery, confirm_delivery})
+        CompoundDelivery{session_id, data: messages, msg_delivery, confirm_delivery}
     }
 }
 
@@ -10,28 +10,57 @@ pub struct BufExchange<'a> {
     exchange: BinaryExchange<'a>,
     max_buf_size: usize,
     bulk_builder: BulkBuilder,
+    /// Keys the bulks are spread among in turn, the exchange output key if empty
+    keys: Vec<String>,
+    next_key: usize,
 }
 
 impl<'a> BufExchange<'a> {
-    pub fn new(exchange: BinaryExchange<'a>) -> Self {
+    /// Buffers messages of a single client session
+    pub fn new(exchange: BinaryExchange<'a>, session_id: u64) -> Self {
         let max_buf_size = MAX_BUF_SIZE;
-        let bulk_builder = BulkBuilder::default();
+        let bulk_builder = BulkBuilder::new(session_id);
         Self {
             exchange,
             max_buf_size,
             bulk_builder,
+            keys: vec![],
+            next_key: 0,
         }
     }
 
+    /// Spreads the bulks among `keys` in turn, instead of sending them with the exchange
+    /// output key
+    pub fn with_keys(mut self, keys: Vec<String>) -> Self {
+        self.keys = keys;
+        self
+    }
+
     pub fn flush(&mut self) -> Result<()> {
         if self.bulk_builder.size() > 0 {
             let msg = self.bulk_builder.build();
-            self.exchange.send(&msg)?;
-            self.exchange.send(&Message::Confirmed)
+            if self.keys.is_empty() {
+                self.exchange.send(&msg)?;
+                return self.exchange.send(&msg.confirm());
+            }
+            let key = &self.keys[self.next_key % self.keys.len()];
+            self.next_key += 1;
+            self.exchange.send_with_key(&msg, key)?;
+            self.exchange.send_with_key(&msg.confirm(), key)
         } else {
             Ok(())
         }
     }
+
+    /// Flushes the buffer and publishes the EOS with `key` instead of the exchange output key
+    pub fn end_of_stream_with_key(&mut self, key: &str) -> Result<()> {
+        self.flush()?;
+        let mut eos = BulkBuilder::new(self.bulk_builder.session_id());
+        eos.push(&Message::EndOfStream);
+        let eos = eos.build();
+        self.exchange.send_with_key(&eos, key)?;
+        self.exchange.send_with_key(&eos.confirm(), key)
+    }
 }
 
 impl Drop for BufExchange<'_> {
@@ -60,7 +89,9 @@ impl RabbitExchange for BufExchange<'_> {
     }
 
     fn end_of_stream(&mut self) -> Result<bool> {
+        // EOS travels inside the bulk so consumers know which session finished
+        self.bulk_builder.push(&Message::EndOfStream);
         self.flush()?;
-        self.exchange.end_of_stream()
+        Ok(true)
     }
 }
@@ -1,4 +1,4 @@
-use crate::messages::Message;
+use crate::messages::{BulkBuilder, Message};
 use crate::middleware::RabbitExchange;
 use crate::Config;
 use amiquip::{
@@ -106,8 +106,11 @@ impl RabbitExchange for BinaryExchange<'_> {
     }
 
     fn end_of_stream(&mut self) -> Result<bool> {
-        self.send(&self.eos_message.clone())?;
-        self.send(&Message::Confirmed)?;
+        let mut eos = BulkBuilder::new(0);
+        eos.push(&self.eos_message);
+        let eos = eos.build();
+        self.send(&eos)?;
+        self.send(&eos.confirm())?;
         Ok(true)
     }
 }
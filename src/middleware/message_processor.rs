@@ -6,13 +6,22 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 
+/// Processes the messages of a single client session. `RabbitService` keeps one processor
+/// per session, cloned from the one it was built with.
 pub trait MessageProcessor {
     type State: Serialize + DeserializeOwned + Debug + std::clone::Clone + std::default::Default;
 
     fn process_message(&mut self, message: Message) -> Option<Message>;
 
-    fn on_stream_finished(&self) -> Option<Message> {
-        None
+    /// Called after the messages of each delivery, and before an end of stream is handled, so
+    /// processors can send what they aggregated from them as a few messages
+    fn on_batch_finished(&mut self) -> Vec<Message> {
+        vec![]
+    }
+
+    /// Called once every producer of the session sent its end of stream
+    fn on_stream_finished(&self) -> Vec<Message> {
+        vec![]
     }
 
     fn send_process_output<E: RabbitExchange>(
@@ -3,6 +3,7 @@ pub mod buf_exchange;
 pub mod connection;
 pub mod consumer;
 pub mod message_processor;
+pub mod progress;
 pub mod service;
 pub mod transaction_log;
 
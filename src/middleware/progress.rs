@@ -0,0 +1,48 @@
+use crate::messages::{ProgressEvent, ProgressUpdate};
+use crate::middleware::connection::RabbitConnection;
+use crate::PROGRESS_EXCHANGE_NAME;
+use amiquip::{Exchange, ExchangeType, Publish, Result};
+use log::warn;
+
+/// Producers publish their ingested rows every this many rows
+pub const ROWS_PER_PROGRESS_EVENT: u64 = 10_000;
+
+/// Publishes progress events of a stage. Events are informative only, so they are
+/// neither confirmed nor logged, and failing to publish one never stops the stage
+pub struct ProgressPublisher<'a> {
+    exchange: Exchange<'a>,
+    stage: String,
+}
+
+impl<'a> ProgressPublisher<'a> {
+    pub fn new(connection: &'a RabbitConnection, stage: &str) -> Result<Self> {
+        let exchange = connection.get_named_exchange(PROGRESS_EXCHANGE_NAME, ExchangeType::Fanout)?;
+        Ok(Self {
+            exchange,
+            stage: stage.to_string(),
+        })
+    }
+
+    pub fn publish(&self, session_id: u64, update: ProgressUpdate) {
+        let event = ProgressEvent {
+            session_id,
+            stage: self.stage.clone(),
+            update,
+        };
+        let body = bincode::serialize(&event).unwrap();
+        if let Err(e) = self.exchange.publish(Publish::new(&body, "")) {
+            warn!("Failed to publish progress of session {}: {:?}", session_id, e);
+        }
+    }
+}
+
+/// Name of the running binary, used as the stage name of its progress events
+pub fn stage_name() -> String {
+    std::env::args()
+        .next()
+        .as_deref()
+        .map(std::path::Path::new)
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
@@ -1,16 +1,18 @@
-use std::ops::Add;
 use super::connection::{BinaryExchange, RabbitConnection};
-use crate::messages::{BulkBuilder, Message};
+use crate::messages::{BulkBuilder, Message, ProgressUpdate};
 use crate::middleware::buf_consumer::BufConsumer;
 use crate::middleware::consumer::DeliveryConsumer;
 use crate::middleware::message_processor::MessageProcessor;
-use crate::middleware::transaction_log::{Checkpoint, TransactionLog};
+use crate::middleware::progress::{self, ProgressPublisher};
+use crate::middleware::transaction_log::{Checkpoint, FinishedSessions, TransactionLog};
 use crate::middleware::RabbitExchange;
 use crate::Config;
 use amiquip::{Channel, Result};
 use envconfig::Envconfig;
 use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
@@ -29,33 +31,144 @@ pub fn init() -> Config {
     env_config
 }
 
-pub struct RabbitService<'a, M: MessageProcessor> {
-    config: Config,
-    message_processor: &'a mut M,
+/// State persisted in a session transaction log
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct SessionState<S> {
+    finished_producers: usize,
+    processor: Option<S>,
+}
+
+struct Session<M: MessageProcessor> {
+    id: u64,
+    processor: M,
     transaction_log: TransactionLog,
-    is_subservice: bool
+    checkpoint: Checkpoint<SessionState<M::State>>,
+    prev_output: BulkBuilder,
+    finished_producers: usize,
 }
 
-impl<'a, M: MessageProcessor> RabbitService<'a, M> {
-    pub fn new(config: Config, message_processor: &'a mut M) -> Self {
-        let transaction_log = TransactionLog::new(&config.transaction_log_path).unwrap();
+impl<M: MessageProcessor> Session<M> {
+    fn new(id: u64, mut processor: M, transaction_log_path: &str) -> Self {
+        let mut transaction_log = TransactionLog::for_session(transaction_log_path, id).unwrap();
+        let (state, prev_output) = transaction_log
+            .load_state::<SessionState<M::State>>()
+            .unwrap_or_default();
+        if let Some(processor_state) = state.processor {
+            processor.set_state(processor_state);
+        }
+        let checkpoint = transaction_log
+            .load_checkpoint::<SessionState<M::State>>()
+            .unwrap_or(Checkpoint::Clean);
         Self {
-            config,
-            message_processor,
+            id,
+            processor,
             transaction_log,
-            is_subservice: false,
+            checkpoint,
+            prev_output,
+            finished_producers: state.finished_producers,
+        }
+    }
+
+    /// Processes a delivery and sends its output. Returns true if the session stream finished
+    fn handle_delivery(
+        &mut self,
+        data: Vec<Message>,
+        exchange: &mut BinaryExchange,
+        producers: usize,
+    ) -> Result<bool> {
+        let mut bulk_builder = BulkBuilder::new(self.id);
+        if matches!(self.checkpoint, Checkpoint::Clean) {
+            for message in data {
+                match message {
+                    Message::EndOfStream => {
+                        for result in self.processor.on_batch_finished() {
+                            bulk_builder.push(&result);
+                        }
+                        self.finished_producers += 1;
+                        if self.finished_producers < producers {
+                            info!("Session {}: producer finished, waiting for {} more", self.id, producers - self.finished_producers);
+                            continue;
+                        }
+                        for result in self.processor.on_stream_finished() {
+                            info!("pushing Result {:?}", result);
+                            bulk_builder.push(&result);
+                        }
+                        // Last of the session, so consumers get the results before closing it
+                        info!("Session {}: stream finished, pushing EOS", self.id);
+                        bulk_builder.push(&Message::EndOfStream);
+                    }
+                    _ => {
+                        if let Some(result) = self.processor.process_message(message) {
+                            bulk_builder.push(&result);
+                        }
+                    }
+                }
+            }
+            for result in self.processor.on_batch_finished() {
+                bulk_builder.push(&result);
+            }
+            let state = SessionState {
+                finished_producers: self.finished_producers,
+                processor: self.processor.get_state(),
+            };
+            self.transaction_log.save_state(state, &bulk_builder).unwrap(); //writeTransactionLog(State, "processed")
+        }
+        if matches!(self.checkpoint, Checkpoint::Processed{state: _, output: _} ) {
+            bulk_builder = self.prev_output.clone();
+        }
+        if bulk_builder.size() > 0 && !matches!(self.checkpoint, Checkpoint::Confirmed) {
+            let output = bulk_builder.build();
+            let confirm = output.confirm();
+            if !matches!(self.checkpoint, Checkpoint::Sent) {
+                self.processor.send_process_output(exchange, output)?;
+                self.transaction_log.save_sent().unwrap();
+            }
+            exchange.send(&confirm)?;
+            self.transaction_log.save_confirmed().unwrap();
+        }
+
+        let stream_finished = self.finished_producers >= producers;
+        if stream_finished {
+            self.transaction_log.save_end_of_stream().unwrap();
         }
+        Ok(stream_finished)
     }
+}
+
+pub struct RabbitService<M: MessageProcessor + Clone> {
+    config: Config,
+    message_processor: M,
+    producers: usize,
+    sessions: HashMap<u64, Session<M>>,
+    /// Sessions whose stream finished. Deliveries arriving for them later are dropped instead
+    /// of opening a session that would never finish
+    finished_sessions: FinishedSessions,
+}
 
-    pub fn new_subservice(mut config: Config, message_processor: &'a mut M) -> Self {
-        config.transaction_log_path = config.transaction_log_path.add(".subservice");
-        let transaction_log = TransactionLog::new(&config.transaction_log_path).unwrap();
-        info!("New transaction log: {:?}", config.transaction_log_path);
+impl<M: MessageProcessor + Clone> RabbitService<M> {
+    /// Each client session gets a clone of `message_processor`
+    pub fn new(config: Config, message_processor: M) -> Self {
+        let producers = str::parse::<usize>(&config.producers).unwrap();
+        let mut sessions = HashMap::new();
+        info!("Loading state");
+        let finished_sessions = FinishedSessions::load(&config.transaction_log_path).unwrap();
+        for session_id in TransactionLog::find_sessions(&config.transaction_log_path).unwrap_or_default() {
+            let session = Session::new(session_id, message_processor.clone(), &config.transaction_log_path);
+            if finished_sessions.contains(session_id) {
+                // Left behind if the service stopped right after the session finished
+                info!("Deleting the log of finished session {}", session_id);
+                session.transaction_log.delete_log().unwrap();
+                continue;
+            }
+            info!("Restoring session {}", session_id);
+            sessions.insert(session_id, session);
+        }
         Self {
             config,
             message_processor,
-            transaction_log,
-            is_subservice: true,
+            producers,
+            sessions,
+            finished_sessions,
         }
     }
 
@@ -68,101 +181,55 @@ impl<'a, M: MessageProcessor> RabbitService<'a, M> {
         let buf_consumer = BufConsumer::new(consumer);
         let exchange = connection.get_direct_exchange();
         let exchange = BinaryExchange::new(exchange, output_key, 1, 1);
+        let progress = ProgressPublisher::new(&connection, &progress::stage_name())?;
 
-        self._run(channel, buf_consumer, exchange, false)?;
+        self._run(channel, buf_consumer, exchange, &progress)?;
         info!("Closing connection");
         connection.close()?;
         info!("Exit");
         Ok(())
     }
 
-    pub fn run_once(&mut self, consumer: &str, output_key: Option<String>) -> Result<()> {
-        let connection = RabbitConnection::new(&self.config)?;
-
-        let channel = connection.get_channel();
-        let consumer = connection.get_consumer(consumer)?;
-        let consumer = DeliveryConsumer::new(consumer);
-        let buf_consumer = BufConsumer::new(consumer);
-        let exchange = connection.get_direct_exchange();
-        let exchange = BinaryExchange::new(exchange, output_key, 1, 1);
-
-        self._run(channel, buf_consumer, exchange, true)?;
-        info!("Exit");
-        connection.close()?;
-        Ok(())
-    }
-
-    fn _run(&mut self, channel: &Channel, buf_consumer: BufConsumer, mut exchange: BinaryExchange, run_once: bool) -> Result<()> {
-        let mut stream_finished = run_once;
-        info!("Loading state");
-        let (state, prev_output) = self.transaction_log.load_state::<M::State>().unwrap_or_default();
-        self.message_processor.set_state(state);
-        let mut checkpoint = self.transaction_log.load_checkpoint::<M::State>().unwrap_or(Checkpoint::Clean);
-        if matches!(checkpoint, Checkpoint::ServiceFinished) {
-            return Ok(());
-        }
+    fn _run(
+        &mut self,
+        channel: &Channel,
+        buf_consumer: BufConsumer,
+        mut exchange: BinaryExchange,
+        progress: &ProgressPublisher,
+    ) -> Result<()> {
         info!("Consuming queue");
-        for compound_delivery in buf_consumer {
-            let mut bulk_builder = BulkBuilder::default();
-            if matches!(checkpoint, Checkpoint::Clean) {
-                for message in compound_delivery.data {
-                    match message {
-                        Message::EndOfStream => {
-                            stream_finished = true;
-                            info!("Stream finished, pushing EOS");
-                            bulk_builder.push(&Message::EndOfStream);
-
-                            if let Some(result) =
-                                self.message_processor.on_stream_finished()
-                            {
-                                info!("pushing Result {:?}", result);
-                                bulk_builder.push(&result);
-                            }
-                        }
-                        _ => {
-                            if let Some(result) =
-                                self.message_processor.process_message(message)
-                            {
-                                bulk_builder.push(&result);
-                            }
-                        }
-                    }
-                }
-                if let Some(state) = self.message_processor.get_state() {
-                    self.transaction_log.save_state(state, &bulk_builder).unwrap(); //writeTransactionLog(State, "processed")
-                }
-            }
-            if matches!(checkpoint, Checkpoint::Processed{state: _, output: _} ) {
-                bulk_builder = prev_output.clone();
-            }
-            if bulk_builder.size() > 0 && !matches!(checkpoint, Checkpoint::Confirmed) {
-                let output = bulk_builder.build();
-                if !matches!(checkpoint, Checkpoint::Sent) {
-                    self.message_processor
-                        .send_process_output(&mut exchange, output)?;
-                    self.transaction_log.save_sent().unwrap();
-                }
-                exchange.send(&Message::Confirmed)?;
-                self.transaction_log.save_confirmed().unwrap();
-            }
-
-            if stream_finished {
-                self.transaction_log.save_end_of_stream().unwrap();
+        for mut compound_delivery in buf_consumer {
+            let session_id = compound_delivery.session_id;
+            if self.finished_sessions.contains(session_id) {
+                warn!(
+                    "Session {}: dropping {} messages that arrived after its stream finished",
+                    session_id,
+                    compound_delivery.data.len()
+                );
+                compound_delivery.ack(channel)?;
+                continue;
             }
+            let session = self.sessions.entry(session_id).or_insert_with(|| {
+                info!("New session {}", session_id);
+                Session::new(session_id, self.message_processor.clone(), &self.config.transaction_log_path)
+            });
+            let messages = compound_delivery.data.len() as u64;
+            let stream_finished =
+                session.handle_delivery(std::mem::take(&mut compound_delivery.data), &mut exchange, self.producers)?;
 
-            // Also ACKs msg_delivery
-            compound_delivery.confirm_delivery.ack_multiple(channel)?;
+            compound_delivery.ack(channel)?;
+            progress.publish(session_id, ProgressUpdate::BatchProcessed(messages));
 
             if stream_finished {
-                if !self.is_subservice {
-                    self.transaction_log.delete_log().unwrap();
-                } else {
-                    self.transaction_log.save_service_finished().unwrap();
+                progress.publish(session_id, ProgressUpdate::StreamFinished);
+                self.finished_sessions.insert(session_id).unwrap();
+                if let Some(session) = self.sessions.remove(&session_id) {
+                    session.transaction_log.delete_log().unwrap();
                 }
-                break
+                continue;
             }
-            self.transaction_log.save_clean().unwrap();
-            checkpoint = Checkpoint::Clean;
+            session.transaction_log.save_clean().unwrap();
+            session.checkpoint = Checkpoint::Clean;
         }
         Ok(())
     }
@@ -1,14 +1,18 @@
 use std::collections::vec_deque::IntoIter;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use serde::{Deserialize, Serialize};
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{BufReader, BufRead, Seek, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::de::DeserializeOwned;
 use serde_json;
 use crate::messages::BulkBuilder;
 
 const MAX_CHECKPOINTS: usize = 20;
+/// Finished sessions are forgotten after this long, late deliveries are expected well before
+const FINISHED_SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum Checkpoint<S> where S: std::clone::Clone {
@@ -17,7 +21,6 @@ pub enum Checkpoint<S> where S: std::clone::Clone {
     Sent,
     Confirmed,
     EndOfStream,
-    ServiceFinished,
 }
 
 pub struct TransactionLog {
@@ -35,6 +38,32 @@ impl TransactionLog {
         Ok(Self { log, path: path.to_string() })
     }
 
+    /// Opens the log of a single session, stored next to the service log
+    pub fn for_session(base_path: &str, session_id: u64) -> io::Result<Self> {
+        Self::new(&format!("{}.{}", base_path, session_id))
+    }
+
+    /// Lists the sessions with a log left by a previous run of the service
+    pub fn find_sessions(base_path: &str) -> io::Result<Vec<u64>> {
+        let base_path = Path::new(base_path);
+        let prefix = match base_path.file_name() {
+            Some(name) => format!("{}.", name.to_string_lossy()),
+            None => return Ok(vec![]),
+        };
+        let dir = match base_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let sessions = std::fs::read_dir(dir)?
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_prefix(&prefix)?.parse::<u64>().ok()
+            })
+            .collect();
+        Ok(sessions)
+    }
+
     pub fn load_state<S: DeserializeOwned + std::fmt::Debug + std::clone::Clone + std::default::Default>(
         &mut self,
     ) -> io::Result<(S, BulkBuilder)> {
@@ -99,10 +128,6 @@ impl TransactionLog {
         self.save_checkpoint::<()>(Checkpoint::EndOfStream)
     }
 
-    pub fn save_service_finished(&mut self) -> io::Result<()> {
-        self.save_checkpoint::<()>(Checkpoint::ServiceFinished)
-    }
-
     fn save_checkpoint<S: Serialize + std::clone::Clone>(&mut self, checkpoint: Checkpoint<S>) -> io::Result<()> {
         let line = serde_json::to_string(&checkpoint).unwrap();
         self.log.write_all(line.as_bytes())?;
@@ -111,6 +136,69 @@ impl TransactionLog {
 
 }
 
+/// Sessions whose stream finished, journaled next to the service log as lines of their id and
+/// finish time, so they are still known after a restart
+pub struct FinishedSessions {
+    path: String,
+    /// Finish time of each session, in seconds since the epoch
+    finished: HashMap<u64, u64>,
+}
+
+impl FinishedSessions {
+    /// Loads the sessions that finished within the TTL, dropping the expired ones from the journal
+    pub fn load(base_path: &str) -> io::Result<Self> {
+        let path = format!("{}.finished", base_path);
+        let finished = match File::open(&path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| {
+                    let (session_id, finished_at) = line.split_once(' ')?;
+                    Some((session_id.parse().ok()?, finished_at.parse().ok()?))
+                })
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let mut sessions = Self { path, finished };
+        sessions.expire()?;
+        Ok(sessions)
+    }
+
+    pub fn contains(&self, session_id: u64) -> bool {
+        self.finished.contains_key(&session_id)
+    }
+
+    pub fn insert(&mut self, session_id: u64) -> io::Result<()> {
+        let now = Self::now();
+        self.finished.insert(session_id, now);
+        if self.expire()? {
+            return Ok(());
+        }
+        let mut journal = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(journal, "{} {}", session_id, now)
+    }
+
+    /// Forgets the sessions past the TTL, rewriting the journal if any was. Returns whether it was
+    fn expire(&mut self) -> io::Result<bool> {
+        let oldest = Self::now().saturating_sub(FINISHED_SESSION_TTL.as_secs());
+        let before = self.finished.len();
+        self.finished.retain(|_, finished_at| *finished_at >= oldest);
+        if self.finished.len() == before {
+            return Ok(false);
+        }
+        let lines: String = self.finished.iter().map(|(session_id, finished_at)| format!("{} {}\n", session_id, finished_at)).collect();
+        let temp_path = format!("{}.tmp", self.path);
+        std::fs::write(&temp_path, lines)?;
+        std::fs::rename(&temp_path, &self.path)?;
+        Ok(true)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+    }
+}
+
 struct LastLines {
     buf: VecDeque<String>
 }
@@ -0,0 +1,166 @@
+use crate::jobs::{JobResults, QueryResults};
+use crate::protocol::{GroupBy, Query};
+use std::io::{self, Error};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A single JSON document with every query
+    Json,
+    /// One JSON file per query
+    JsonPerQuery,
+    /// One CSV file per query
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "json" => Ok(OutputFormat::Json),
+            "json_per_query" => Ok(OutputFormat::JsonPerQuery),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("Unknown output format: {}", name)),
+        }
+    }
+}
+
+/// Writes a temporary file and renames it, so a crash never leaves a half written file
+pub fn write_atomically(path: &str, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Writes `results` next to `base_path`, as `{base_path}.json` or one `{base_path}.{query}.{ext}`
+/// per query that has a result plus a `{base_path}.status.json` with the missing queries and
+/// rejected rows. College keyword hits, if any, go to `{base_path}.college_keyword_hits.{ext}`.
+/// Returns the written paths
+pub fn write_results(base_path: &str, results: &JobResults, format: OutputFormat) -> io::Result<Vec<String>> {
+    if format == OutputFormat::Json {
+        let path = format!("{}.json", base_path);
+        write_atomically(&path, &serde_json::to_vec_pretty(results)?)?;
+        return Ok(vec![path]);
+    }
+    let mut paths = vec![];
+    for query in Query::ALL {
+        let contents = match format {
+            OutputFormat::Csv => query_csv(results, query)?,
+            _ => query_json(results, query)?,
+        };
+        if let Some(contents) = contents {
+            let extension = if format == OutputFormat::Csv { "csv" } else { "json" };
+            let path = format!("{}.{}.{}", base_path, query_name(query), extension);
+            write_atomically(&path, &contents)?;
+            paths.push(path);
+        }
+    }
+    if !results.college_keyword_hits.is_empty() {
+        let (contents, extension) = match format {
+            OutputFormat::Csv => (keyword_hits_csv(results)?, "csv"),
+            _ => (serde_json::to_vec_pretty(&serde_json::json!({ "college_keyword_hits": results.college_keyword_hits }))?, "json"),
+        };
+        let path = format!("{}.college_keyword_hits.{}", base_path, extension);
+        write_atomically(&path, &contents)?;
+        paths.push(path);
+    }
+    // Written even if there are none, so a complete job can be told from a partial one
+    let path = format!("{}.status.json", base_path);
+    let status = serde_json::json!({ "missing": results.missing, "rejected": results.rejected });
+    write_atomically(&path, &serde_json::to_vec_pretty(&status)?)?;
+    paths.push(path);
+    Ok(paths)
+}
+
+fn keyword_hits_csv(results: &JobResults) -> io::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["keyword", "comments"])?;
+    for (keyword, comments) in &results.college_keyword_hits {
+        writer.write_record([keyword.clone(), comments.to_string()])?;
+    }
+    writer.into_inner().map_err(|e| Error::other(e.to_string()))
+}
+
+fn query_name(query: Query) -> &'static str {
+    match query {
+        Query::ScoreMean => "score_mean",
+        Query::BestMeme => "best_meme",
+        Query::CollegePosts => "college_posts",
+    }
+}
+
+fn query_json(results: &JobResults, query: Query) -> io::Result<Option<Vec<u8>>> {
+    let value = match results.group_by {
+        GroupBy::None => query_value(&results.global, query),
+        GroupBy::Subreddit => {
+            let groups: serde_json::Map<String, serde_json::Value> = results
+                .groups()
+                .into_iter()
+                .filter_map(|(group, results)| Some((group.to_string(), query_value(results, query)?)))
+                .collect();
+            (!groups.is_empty()).then_some(serde_json::Value::Object(groups))
+        }
+    };
+    value
+        .map(|value| serde_json::to_vec_pretty(&serde_json::json!({ query_name(query): value })))
+        .transpose()
+        .map_err(Error::from)
+}
+
+fn query_value(results: &QueryResults, query: Query) -> Option<serde_json::Value> {
+    match query {
+        Query::ScoreMean => results.score_stats.as_ref().map(|stats| serde_json::json!(stats)),
+        Query::BestMeme => results.best_memes.as_ref().map(|memes| serde_json::json!(memes)),
+        Query::CollegePosts => results.college_posts.as_ref().map(|urls| serde_json::json!(urls)),
+    }
+}
+
+/// Grouped results get a leading subreddit column
+fn query_csv(results: &JobResults, query: Query) -> io::Result<Option<Vec<u8>>> {
+    let grouped = results.group_by == GroupBy::Subreddit;
+    let mut header = None;
+    let mut rows = vec![];
+    for (group, results) in results.groups() {
+        let Some((group_header, group_rows)) = query_rows(results, query) else {
+            continue;
+        };
+        header = Some(group_header);
+        for row in group_rows {
+            rows.push(if grouped { [vec![group.to_string()], row].concat() } else { row });
+        }
+    }
+    let Some(header) = header else {
+        return Ok(None);
+    };
+    let header = if grouped { [&["subreddit"], header].concat() } else { header.to_vec() };
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(header)?;
+    for row in rows {
+        writer.write_record(row)?;
+    }
+    writer.into_inner().map(Some).map_err(|e| Error::other(e.to_string()))
+}
+
+fn query_rows(results: &QueryResults, query: Query) -> Option<(&'static [&'static str], Vec<Vec<String>>)> {
+    match query {
+        Query::ScoreMean => results.score_stats.as_ref().map(|stats| {
+            let header: &[&str] = &["count", "mean", "variance", "stddev", "min", "max", "median", "p90", "p99"];
+            let values = [stats.count as f64, stats.mean, stats.variance, stats.stddev, stats.min, stats.max, stats.median, stats.p90, stats.p99];
+            (header, vec![values.iter().map(f64::to_string).collect()])
+        }),
+        Query::BestMeme => results.best_memes.as_ref().map(|memes| {
+            let header: &[&str] = &["rank", "id", "url", "sentiment"];
+            let rows = memes
+                .iter()
+                .enumerate()
+                .map(|(rank, meme)| vec![(rank + 1).to_string(), meme.id.clone(), meme.url.clone(), meme.sentiment.to_string()])
+                .collect();
+            (header, rows)
+        }),
+        Query::CollegePosts => results.college_posts.as_ref().map(|urls| {
+            let header: &[&str] = &["url"];
+            (header, urls.iter().map(|url| vec![url.clone()]).collect())
+        }),
+    }
+}
@@ -1,6 +1,8 @@
-use csv::{Reader, ReaderBuilder, StringRecord};
+use crate::protocol::{GroupBy, RejectReason, NO_SUBREDDIT};
+use crate::rows::RecordIterator;
+use crate::schema::{self, ColumnMap};
+use csv::StringRecord;
 use serde::{Deserialize, Serialize};
-use std::net::TcpStream;
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Post {
@@ -12,50 +14,35 @@ pub struct Post {
     pub url: String,
     pub body: String,
     pub score: u32,
+    /// Results the post counts towards: its subreddit id if the session is grouped by
+    /// subreddit, empty otherwise
+    #[serde(default)]
+    pub group: String,
 }
 
 impl Post {
-    fn from(record: StringRecord) -> Option<Self> {
-        Some(Self {
-            id: record.get(1)?.to_string(),
-            subreddit_id: record.get(2)?.to_string(),
-            nsfw: record.get(4)?.eq("true"),
-            created: str::parse::<u64>(record.get(5)?).expect("Created timestamp is invalid"),
-            permalink: record.get(6)?.to_string(),
-            url: record.get(8)?.to_string(),
-            body: record.get(9)?.to_string(),
-            score: str::parse::<u32>(record.get(11)?).expect("Score is invalid"),
+    pub fn parse(record: &StringRecord, columns: &ColumnMap) -> Result<Self, RejectReason> {
+        Ok(Self {
+            id: columns.value(record, schema::ID)?.to_string(),
+            subreddit_id: columns.value(record, schema::SUBREDDIT_ID)?.to_string(),
+            nsfw: columns.value(record, schema::NSFW)?.eq("true"),
+            created: columns.parse::<u64>(record, schema::CREATED)?,
+            permalink: columns.value(record, schema::PERMALINK)?.to_string(),
+            url: columns.value(record, schema::URL)?.to_string(),
+            body: columns.value(record, schema::SELFTEXT)?.to_string(),
+            score: columns.parse::<u32>(record, schema::SCORE)?,
+            group: String::new(),
         })
     }
-}
-
-pub struct PostIterator {
-    reader: Reader<TcpStream>,
-}
-
-impl PostIterator {
-    // pub fn from_file(path: &str) -> Self {
-    //     let post_file = File::open(path).unwrap();
-    //     let reader = ReaderBuilder::new()
-    //         .has_headers(true)
-    //         .from_reader(post_file);
-    //     Self { reader }
-    // }
 
-    pub fn from_stream(stream: TcpStream) -> Self {
-        let reader = ReaderBuilder::new()
-            .has_headers(true)
-            .from_reader(stream);
-        Self { reader }
+    pub fn set_group(&mut self, group_by: GroupBy) {
+        self.group = match group_by {
+            GroupBy::None => String::new(),
+            GroupBy::Subreddit if self.subreddit_id.is_empty() => NO_SUBREDDIT.to_string(),
+            GroupBy::Subreddit => self.subreddit_id.clone(),
+        };
     }
 }
 
-impl Iterator for PostIterator {
-    type Item = Post;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let record_res = self.reader.records().next()?;
-        let record = record_res.ok()?;
-        Post::from(record)
-    }
-}
+/// Reads posts from any source: a client stream, a local file, stdin or a buffer
+pub type PostIterator<R> = RecordIterator<R, Post>;
@@ -0,0 +1,381 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+/// Sent by both peers when the connection opens, followed by their protocol version
+const MAGIC: [u8; 4] = *b"MEME";
+/// Bumped on every incompatible change to the frames
+pub const PROTOCOL_VERSION: u16 = 15;
+/// Frames are rejected above this size, so peers can't make us allocate arbitrary buffers
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// Bytes of an `Upload` frame besides its data, with room to spare for the other frames of an
+/// upload
+pub const UPLOAD_FRAME_OVERHEAD: usize = 64;
+
+/// Numeric error codes sent to clients
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u16", try_from = "u16")]
+pub enum ErrorCode {
+    /// Every job slot is taken and the waiting queue is full
+    ServerBusy = 1,
+    /// The pipeline couldn't process the job
+    ServerNotAvailable = 2,
+    /// The client sent a frame that wasn't expected at that point
+    InvalidRequest = 3,
+    /// The upload to resume doesn't exist or already finished
+    UnknownUpload = 4,
+    /// No job with the requested id
+    UnknownJob = 5,
+    /// The job results were requested before it finished
+    JobNotFinished = 6,
+    /// The job was cancelled by a client
+    JobCancelled = 7,
+    /// The job to cancel already finished
+    JobAlreadyFinished = 8,
+    /// An uploaded file is larger than the server accepts
+    UploadTooLarge = 9,
+    /// An upload chunk is larger than the server accepts
+    ChunkTooLarge = 10,
+    /// An uploaded file doesn't have the expected header, fields or rows
+    InvalidCsv = 11,
+    /// An uploaded file isn't valid data of the compression it was declared with
+    InvalidCompression = 12,
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        code as u16
+    }
+}
+
+impl TryFrom<u16> for ErrorCode {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(ErrorCode::ServerBusy),
+            2 => Ok(ErrorCode::ServerNotAvailable),
+            3 => Ok(ErrorCode::InvalidRequest),
+            4 => Ok(ErrorCode::UnknownUpload),
+            5 => Ok(ErrorCode::UnknownJob),
+            6 => Ok(ErrorCode::JobNotFinished),
+            7 => Ok(ErrorCode::JobCancelled),
+            8 => Ok(ErrorCode::JobAlreadyFinished),
+            9 => Ok(ErrorCode::UploadTooLarge),
+            10 => Ok(ErrorCode::ChunkTooLarge),
+            11 => Ok(ErrorCode::InvalidCsv),
+            12 => Ok(ErrorCode::InvalidCompression),
+            _ => Err(format!("Unknown error code: {}", code)),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({})", self, *self as u16)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileKind {
+    Posts,
+    Comments,
+}
+
+/// How the client encoded the files it uploads
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+/// Layout of the uploaded files. Either may be gzipped on top of the upload compression
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputFormat {
+    /// Detected from the first bytes of each file
+    #[default]
+    Auto,
+    /// CSV with a header row
+    Csv,
+    /// One JSON object per line
+    JsonLines,
+}
+
+impl InputFormat {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(InputFormat::Auto),
+            1 => Some(InputFormat::Csv),
+            2 => Some(InputFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim() {
+            "auto" => Ok(InputFormat::Auto),
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::JsonLines),
+            _ => Err(format!("Unknown input format: {}", name)),
+        }
+    }
+}
+
+/// How the results of a job are grouped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupBy {
+    /// A single result per query
+    #[default]
+    None,
+    /// A result per query and subreddit
+    Subreddit,
+}
+
+/// Group of the rows without a subreddit id when grouping by subreddit
+pub const NO_SUBREDDIT: &str = "<none>";
+
+impl GroupBy {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(GroupBy::None),
+            1 => Some(GroupBy::Subreddit),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim() {
+            "none" => Ok(GroupBy::None),
+            "subreddit" => Ok(GroupBy::Subreddit),
+            _ => Err(format!("Unknown grouping: {}", name)),
+        }
+    }
+}
+
+/// How the client sends the files of a job
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadEncoding {
+    pub compression: Compression,
+    pub format: InputFormat,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Uploading,
+    Processing,
+    Finished,
+    Failed { reason: String },
+    Cancelled,
+}
+
+/// Totals of a pipeline stage for a single job
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StageProgress {
+    /// Rows ingested by producers, or messages processed by any other stage
+    pub rows: u64,
+    pub batches: u64,
+    /// Every input of the stage reached its end of stream
+    pub finished: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Progress {
+    /// The job waits for a free slot at this position of the queue
+    Queued { position: usize },
+    /// A pipeline stage advanced since its last progress frame
+    Stage { stage: String, progress: StageProgress },
+}
+
+/// Queries answered for every job
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Query {
+    ScoreMean,
+    BestMeme,
+    CollegePosts,
+}
+
+impl Query {
+    pub const ALL: [Query; 3] = [Query::ScoreMean, Query::BestMeme, Query::CollegePosts];
+
+    /// Packs a query set in a byte, one bit per query
+    pub fn to_mask(queries: &[Query]) -> u8 {
+        queries.iter().fold(0, |mask, query| mask | 1 << *query as u8)
+    }
+
+    pub fn from_mask(mask: u8) -> Vec<Query> {
+        Query::ALL.into_iter().filter(|query| mask & 1 << *query as u8 != 0).collect()
+    }
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim() {
+            "score_mean" => Ok(Query::ScoreMean),
+            "best_meme" => Ok(Query::BestMeme),
+            "college_posts" => Ok(Query::CollegePosts),
+            _ => Err(format!("Unknown query: {}", name)),
+        }
+    }
+}
+
+/// Why a producer skipped a row of an uploaded file
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// Not valid CSV, or its field count differs from the header's
+    Malformed,
+    /// The value of this column has the wrong type
+    InvalidValue(String),
+    /// The row lacks this column, only JSON Lines rows can
+    Missing(String),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Malformed => write!(f, "malformed"),
+            RejectReason::InvalidValue(column) => write!(f, "invalid {}", column),
+            RejectReason::Missing(column) => write!(f, "missing {}", column),
+        }
+    }
+}
+
+/// Rows of an uploaded file skipped for the same reason
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RejectedRows {
+    pub file: FileKind,
+    pub reason: RejectReason,
+    pub rows: u64,
+}
+
+/// Distribution of the post scores. Percentiles are approximate
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScoreSummary {
+    pub count: u64,
+    pub mean: f64,
+    pub variance: f64,
+    pub stddev: f64,
+    pub min: f64,
+    pub max: f64,
+    pub median: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+/// A post of the best meme ranking, by the mean sentiment of its comments
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RankedMeme {
+    pub id: String,
+    pub url: String,
+    pub sentiment: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// The first field is the subreddit id the result belongs to, empty unless the job is grouped
+/// by subreddit
+pub enum QueryResult {
+    ScoreStats(String, ScoreSummary),
+    /// Best first
+    BestMemes(String, Vec<RankedMeme>),
+    CollegePost(String, String),
+    /// Comments that matched each college keyword, over the whole job
+    CollegeKeywordHits(BTreeMap<String, u64>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Frame {
+    /// Client starts a new upload, asking for these queries only. Upload offsets count the
+    /// bytes as sent, compressed or not
+    Start { queries: Vec<Query>, encoding: UploadEncoding, group_by: GroupBy },
+    /// Client reconnects to an interrupted upload
+    Resume { upload_id: u64 },
+    /// Client asks for the status of a job
+    Status { job_id: u64 },
+    /// Client asks for the results of a finished job
+    Fetch { job_id: u64 },
+    /// Client asks to stop a running job. Answered with its new `JobStatus`
+    Cancel { job_id: u64 },
+    /// Server is ready to receive the upload
+    Ready { upload_id: u64 },
+    /// Server continues an interrupted upload from this position
+    Resumed { file: FileKind, offset: u64 },
+    /// Chunk of an uploaded file starting at `offset`. An empty chunk ends the file
+    Upload { file: FileKind, offset: u64, data: Vec<u8> },
+    /// Every byte of the file before `offset` was forwarded to the pipeline
+    UploadAck { file: FileKind, offset: u64 },
+    /// The upload finished and the job is being processed. The id can be used to poll it later
+    Submitted { job_id: u64 },
+    JobStatus { job_id: u64, status: JobStatus },
+    Progress(Progress),
+    Result(QueryResult),
+    Error { code: ErrorCode, message: String },
+    /// Rows the producers skipped, so the results leave them out. Sent before `Finished`
+    Rejected { rows: Vec<RejectedRows> },
+    /// The job reached its deadline before these queries finished. Sent right before
+    /// `Finished`, so the results already sent are only partial
+    Partial { missing: Vec<Query> },
+    /// Every result was sent
+    Finished,
+}
+
+/// Exchanges protocol versions with the peer. Returns the peer version
+pub fn handshake<S: Read + Write>(stream: &mut S) -> io::Result<u16> {
+    let mut hello = Vec::with_capacity(MAGIC.len() + 2);
+    hello.extend_from_slice(&MAGIC);
+    hello.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    stream.write_all(&hello)?;
+    let mut peer_hello = [0u8; 6];
+    stream.read_exact(&mut peer_hello)?;
+    if peer_hello[..4] != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Peer is not speaking the memes protocol"));
+    }
+    Ok(u16::from_be_bytes([peer_hello[4], peer_hello[5]]))
+}
+
+/// Writes a frame preceded by its 4 bytes big endian size
+pub fn write_frame<W: Write>(stream: &mut W, frame: &Frame) -> io::Result<()> {
+    let body = bincode::serialize(frame).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    stream.write_all(&(body.len() as u32).to_be_bytes())?;
+    stream.write_all(&body)
+}
+
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Frame> {
+    read_frame_limited(stream, MAX_FRAME_SIZE)
+}
+
+/// Reads a frame, rejecting it with `FileTooLarge` before allocating its buffer if it exceeds
+/// `max_size` bytes
+pub fn read_frame_limited<R: Read>(stream: &mut R, max_size: usize) -> io::Result<Frame> {
+    let mut size = [0u8; 4];
+    stream.read_exact(&mut size)?;
+    let size = u32::from_be_bytes(size) as usize;
+    if size > max_size {
+        return Err(Error::new(
+            ErrorKind::FileTooLarge,
+            format!("Frame of {} bytes exceeds the limit of {}", size, max_size),
+        ));
+    }
+    let mut body = vec![0; size];
+    stream.read_exact(&mut body)?;
+    bincode::deserialize(&body).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
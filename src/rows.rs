@@ -0,0 +1,354 @@
+//! Rows of an uploaded file, whatever its format. CSV and JSON Lines files, gzipped or not, are
+//! read as records laid out as the columns of the file, so posts and comments parse them alike.
+
+use crate::protocol::{FileKind, InputFormat, RejectReason};
+use crate::schema::{self, ColumnAliases, ColumnMap, Rejects};
+use csv::{Reader, ReaderBuilder, StringRecord};
+use flate2::bufread::MultiGzDecoder;
+use log::error;
+use serde_json::{Map, Number, Value};
+use std::io::{self, BufRead, BufReader, Read};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Debug)]
+pub enum RowError {
+    /// The row couldn't be read at all, the next one may be
+    Bad { line: u64, error: String },
+    /// The row was read but lacks a required column, the next one may not
+    Missing { line: u64, column: String },
+    /// The source failed, no more rows can be read
+    Io(io::Error),
+}
+
+/// Source bytes, decompressed if they start as a gzip file
+enum Decoded<R: Read> {
+    Plain(BufReader<R>),
+    Gzip(BufReader<MultiGzDecoder<BufReader<R>>>),
+}
+
+impl<R: Read> Decoded<R> {
+    fn new(source: R) -> io::Result<Self> {
+        let mut reader = BufReader::new(source);
+        if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            Ok(Decoded::Gzip(BufReader::new(MultiGzDecoder::new(reader))))
+        } else {
+            Ok(Decoded::Plain(reader))
+        }
+    }
+}
+
+impl<R: Read> Read for Decoded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoded::Plain(reader) => reader.read(buf),
+            Decoded::Gzip(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<R: Read> BufRead for Decoded<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Decoded::Plain(reader) => reader.fill_buf(),
+            Decoded::Gzip(reader) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        match self {
+            Decoded::Plain(reader) => reader.consume(amount),
+            Decoded::Gzip(reader) => reader.consume(amount),
+        }
+    }
+}
+
+enum Format<R: Read> {
+    Csv(Reader<Decoded<R>>),
+    JsonLines {
+        lines: Decoded<R>,
+        line: u64,
+        aliases: ColumnAliases,
+    },
+}
+
+pub struct RowReader<R: Read> {
+    file: FileKind,
+    format: Format<R>,
+    columns: ColumnMap,
+}
+
+impl<R: Read> RowReader<R> {
+    /// Reads the start of `source` to detect its compression and, unless declared, its format.
+    /// CSV files fail here if their header lacks a required column
+    pub fn new(file: FileKind, source: R, format: InputFormat, aliases: &ColumnAliases) -> Result<Self, String> {
+        let mut decoded = Decoded::new(source).map_err(|e| e.to_string())?;
+        let start = decoded.fill_buf().map_err(|e| e.to_string())?;
+        if start.is_empty() {
+            return Err(format!("The {:?} file is empty", file));
+        }
+        let format = match format {
+            InputFormat::Auto if start.trim_ascii_start().starts_with(b"{") => InputFormat::JsonLines,
+            InputFormat::Auto => InputFormat::Csv,
+            declared => declared,
+        };
+        match format {
+            InputFormat::JsonLines => {
+                let header = StringRecord::from(schema::column_names(file));
+                Ok(Self {
+                    file,
+                    format: Format::JsonLines { lines: decoded, line: 0, aliases: aliases.clone() },
+                    columns: ColumnMap::new(file, &header, &ColumnAliases::default())?,
+                })
+            }
+            _ => {
+                let mut reader = ReaderBuilder::new().has_headers(true).from_reader(decoded);
+                let header = reader.headers().map_err(|e| e.to_string())?;
+                let columns = ColumnMap::new(file, header, aliases)?;
+                Ok(Self { file, format: Format::Csv(reader), columns })
+            }
+        }
+    }
+
+    pub fn columns(&self) -> &ColumnMap {
+        &self.columns
+    }
+
+    /// Reads the next row into `record`, returning its line. None once the source ends
+    pub fn read(&mut self, record: &mut StringRecord) -> Result<Option<u64>, RowError> {
+        match &mut self.format {
+            Format::Csv(reader) => match reader.read_record(record) {
+                Ok(false) => Ok(None),
+                Ok(true) => Ok(Some(record.position().map(|position| position.line()).unwrap_or_default())),
+                Err(e) if e.is_io_error() => Err(RowError::Io(e.into())),
+                Err(e) => {
+                    let line = e.position().map(|position| position.line()).unwrap_or_default();
+                    Err(RowError::Bad { line, error: e.to_string() })
+                }
+            },
+            Format::JsonLines { lines, line, aliases } => {
+                let mut text = Vec::new();
+                loop {
+                    text.clear();
+                    if lines.read_until(b'\n', &mut text).map_err(RowError::Io)? == 0 {
+                        return Ok(None);
+                    }
+                    *line += 1;
+                    if !text.trim_ascii().is_empty() {
+                        break;
+                    }
+                }
+                let missing = json_record(self.file, &text, aliases, record).map_err(|error| RowError::Bad { line: *line, error })?;
+                match missing {
+                    Some(column) => Err(RowError::Missing { line: *line, column: column.to_string() }),
+                    None => Ok(Some(*line)),
+                }
+            }
+        }
+    }
+}
+
+/// Fills `record` with the columns of `file` found in a JSON object. Nested objects are read
+/// as dotted names, so `{"subreddit": {"id": ..}}` fills `subreddit.id`. Missing fields are left
+/// empty, returning the first required column missing, while null ones are just empty
+fn json_record(file: FileKind, text: &[u8], aliases: &ColumnAliases, record: &mut StringRecord) -> Result<Option<&'static str>, String> {
+    let object: Map<String, Value> = serde_json::from_slice(text).map_err(|e| e.to_string())?;
+    let mut fields = Vec::new();
+    flatten("", &object, &mut fields);
+    record.clear();
+    let mut missing = None;
+    for column in schema::column_names(file) {
+        let value = fields.iter().find(|(name, _)| aliases.matches(column, name)).map(|(_, value)| value.as_str());
+        if value.is_none() && !schema::is_optional(file, column) {
+            missing = missing.or(Some(column));
+        }
+        record.push_field(value.unwrap_or_default());
+    }
+    Ok(missing)
+}
+
+fn flatten(prefix: &str, object: &Map<String, Value>, fields: &mut Vec<(String, String)>) {
+    for (name, value) in object {
+        let name = format!("{}{}", prefix, name);
+        match value {
+            Value::Object(inner) => flatten(&format!("{}.", name), inner, fields),
+            Value::String(text) => fields.push((name, text.clone())),
+            Value::Null => fields.push((name, String::new())),
+            Value::Number(number) => fields.push((name, integral(number).unwrap_or_else(|| number.to_string()))),
+            value => fields.push((name, value.to_string())),
+        }
+    }
+}
+
+/// Digits of a float without a fractional part, like `1.5e9` or `12.0`, so integer columns
+/// parse it. None for integers and any other float
+fn integral(number: &Number) -> Option<String> {
+    let float = number.as_f64().filter(|_| number.is_f64())?;
+    (float.fract() == 0.0 && float.abs() < u64::MAX as f64).then(|| format!("{:.0}", float))
+}
+
+/// Parses a row laid out as the columns of its file
+pub type ParseRecord<T> = fn(&StringRecord, &ColumnMap) -> Result<T, RejectReason>;
+
+/// Reads records of a file from any source: a client stream, a local file, stdin or a buffer
+pub struct RecordIterator<R: Read, T> {
+    rows: RowReader<R>,
+    parse: ParseRecord<T>,
+    rejects: Rejects,
+}
+
+impl<R: Read, T> RecordIterator<R, T> {
+    /// Reads the start of `source`, failing if it's a CSV whose header lacks any column the
+    /// records need
+    pub fn from_reader(file: FileKind, source: R, format: InputFormat, aliases: &ColumnAliases, parse: ParseRecord<T>) -> Result<Self, String> {
+        let rows = RowReader::new(file, source, format, aliases)?;
+        Ok(Self { rows, parse, rejects: Rejects::new(file) })
+    }
+
+    /// Saves the rows skipped from now on to a CSV file at `path`
+    pub fn save_rejects_to(&mut self, path: &str) -> io::Result<()> {
+        self.rejects.save_to(path)
+    }
+
+    pub fn rejects(&self) -> &Rejects {
+        &self.rejects
+    }
+}
+
+impl<R: Read, T> Iterator for RecordIterator<R, T> {
+    type Item = T;
+
+    /// Skips the rows that can't be parsed, ending only when the stream does
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = StringRecord::new();
+        loop {
+            match self.rows.read(&mut record) {
+                Ok(None) => return None,
+                Ok(Some(line)) => match (self.parse)(&record, self.rows.columns()) {
+                    Ok(parsed) => return Some(parsed),
+                    Err(reason) => self.rejects.add(line, reason, Some(&record)),
+                },
+                Err(RowError::Bad { line, .. }) => self.rejects.add(line, RejectReason::Malformed, None),
+                Err(RowError::Missing { line, column }) => {
+                    self.rejects.add(line, RejectReason::Missing(column), Some(&record))
+                }
+                Err(RowError::Io(e)) => {
+                    error!("Failed to read the {:?} file: {:?}", self.rows.file, e);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comment::{Comment, CommentIterator};
+    use crate::post::{Post, PostIterator};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const POSTS_CSV: &str = "\
+type,id,subreddit.id,subreddit.name,subreddit.nsfw,created_utc,permalink,domain,url,selftext,title,score
+post,p1,2qh1i,askreddit,false,1635700000,https://old.reddit.com/r/AskReddit/comments/p1/,self.askreddit,https://i.redd.it/a.jpg,,Title,12
+post,p2,2qh1i,askreddit,false,yesterday,https://old.reddit.com/r/AskReddit/comments/p2/,self.askreddit,,,Title,3
+post,p3,2qh1i,askreddit,false
+post,p4,2qh1i,askreddit,true,1635700100,https://old.reddit.com/r/AskReddit/comments/p4/,self.askreddit,,text,Title,7
+";
+
+    const COMMENTS_JSONL: &str = r#"{"id": "c1", "subreddit": {"id": "2qh1i", "nsfw": false}, "created_utc": 1635700000, "permalink": "https://old.reddit.com/r/AskReddit/comments/p1/x/c1/", "body": "my university", "sentiment": 0.5, "score": 4}
+
+{"id": "c2", "subreddit_id": "2qh1i", "over_18": false, "created_utc": null, "permalink": "/r/AskReddit/comments/p1/x/c2/", "body": "hi", "sentiment": null, "score": 1, "link_id": "t3_p1"}
+not json
+{"id": "c4", "subreddit_id": "2qh1i", "over_18": false, "created_utc": 1635700000, "permalink": "", "body": "", "sentiment": "positive", "score": 1}
+{"subreddit_id": "2qh1i", "over_18": false, "created_utc": 1635700000, "permalink": "", "body": "", "sentiment": 0.1, "score": 1}
+{"id": "c6", "subreddit_id": "2qh1i", "over_18": false, "created_utc": 1.5e9, "permalink": "", "body": "", "sentiment": 0.1, "score": -2.0}
+"#;
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn rejected(rejects: &Rejects) -> Vec<(RejectReason, u64)> {
+        rejects.totals().into_iter().map(|rejected| (rejected.reason, rejected.rows)).collect()
+    }
+
+    #[test]
+    fn csv_rows_are_parsed_and_bad_ones_rejected() {
+        let aliases = ColumnAliases::default();
+        let mut posts = PostIterator::from_reader(FileKind::Posts, POSTS_CSV.as_bytes(), InputFormat::Auto, &aliases, Post::parse).unwrap();
+        let ids: Vec<String> = posts.by_ref().map(|post| post.id).collect();
+        assert_eq!(ids, ["p1", "p4"]);
+        assert_eq!(
+            rejected(posts.rejects()),
+            [(RejectReason::Malformed, 1), (RejectReason::InvalidValue(schema::CREATED.to_string()), 1)]
+        );
+    }
+
+    #[test]
+    fn json_lines_are_flattened_and_bad_ones_rejected() {
+        let aliases = ColumnAliases::default();
+        let mut comments =
+            CommentIterator::from_reader(FileKind::Comments, COMMENTS_JSONL.as_bytes(), InputFormat::Auto, &aliases, Comment::parse).unwrap();
+        let parsed: Vec<Comment> = comments.by_ref().collect();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0].created, Some(1635700000));
+        assert_eq!(parsed[0].sentiment, Some(0.5));
+        assert_eq!(parsed[1].created, None);
+        assert_eq!(parsed[1].parse_post_id().as_deref(), Some("p1"));
+        // Whole floats parse as integers
+        assert_eq!((parsed[2].created, parsed[2].score), (Some(1500000000), Some(-2)));
+        assert_eq!(
+            rejected(comments.rejects()),
+            [
+                (RejectReason::Malformed, 1),
+                (RejectReason::InvalidValue(schema::SENTIMENT.to_string()), 1),
+                (RejectReason::Missing(schema::ID.to_string()), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn gzipped_files_are_decompressed() {
+        let aliases = ColumnAliases::default();
+        let posts = gzip(POSTS_CSV);
+        let ids: Vec<String> = PostIterator::from_reader(FileKind::Posts, posts.as_slice(), InputFormat::Auto, &aliases, Post::parse)
+            .unwrap()
+            .map(|post| post.id)
+            .collect();
+        assert_eq!(ids, ["p1", "p4"]);
+
+        let comments = gzip(COMMENTS_JSONL);
+        let mut rows = RowReader::new(FileKind::Comments, comments.as_slice(), InputFormat::Auto, &aliases).unwrap();
+        let mut record = StringRecord::new();
+        assert_eq!(rows.read(&mut record).unwrap(), Some(1));
+        assert_eq!(rows.columns().get(&record, schema::ID), Some("c1"));
+        assert_eq!(rows.columns().get(&record, schema::SUBREDDIT_ID), Some("2qh1i"));
+        // The blank line is skipped but still counted
+        assert_eq!(rows.read(&mut record).unwrap(), Some(3));
+        assert!(matches!(rows.read(&mut record), Err(RowError::Bad { line: 4, .. })));
+    }
+
+    #[test]
+    fn csv_header_lacking_columns_is_refused() {
+        let aliases = ColumnAliases::default();
+        let error = RowReader::new(FileKind::Posts, "id,score\np1,3\n".as_bytes(), InputFormat::Csv, &aliases).err().unwrap();
+        assert!(error.contains("lacks the required columns"), "{}", error);
+        assert!(RowReader::new(FileKind::Posts, "".as_bytes(), InputFormat::Auto, &aliases).is_err());
+    }
+
+    #[test]
+    fn aliased_columns_are_found() {
+        let aliases: ColumnAliases = "score=ups".parse().unwrap();
+        let csv = POSTS_CSV.replacen(",score\n", ",ups\n", 1);
+        let scores: Vec<u32> = PostIterator::from_reader(FileKind::Posts, csv.as_bytes(), InputFormat::Csv, &aliases, Post::parse)
+            .unwrap()
+            .map(|post| post.score)
+            .collect();
+        assert_eq!(scores, [12, 7]);
+    }
+}
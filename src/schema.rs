@@ -0,0 +1,234 @@
+//! Layout of the uploaded files. CSV columns are found by their header name, so files may
+//! reorder them or add others. The server checks the header and first rows of every upload
+//! before forwarding it, so producers never get a file they can't parse. Rows with bad values
+//! are rejected by the producers.
+
+use crate::comment::Comment;
+use crate::post::Post;
+use crate::protocol::{FileKind, InputFormat, RejectReason, RejectedRows};
+use crate::rows::{RowError, RowReader};
+use csv::{StringRecord, Writer, WriterBuilder};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::str::FromStr;
+
+/// Rows after the header that are validated
+pub const VALIDATED_ROWS: usize = 10;
+
+pub const ID: &str = "id";
+pub const SUBREDDIT_ID: &str = "subreddit.id";
+pub const NSFW: &str = "subreddit.nsfw";
+pub const CREATED: &str = "created_utc";
+pub const PERMALINK: &str = "permalink";
+pub const URL: &str = "url";
+pub const SELFTEXT: &str = "selftext";
+pub const BODY: &str = "body";
+pub const SENTIMENT: &str = "sentiment";
+pub const SCORE: &str = "score";
+/// Post a comment belongs to, as a bare or `t3_` prefixed id
+pub const LINK_ID: &str = "link_id";
+
+/// Columns read from posts, any other is ignored
+const POST_COLUMNS: [&str; 8] = [ID, SUBREDDIT_ID, NSFW, CREATED, PERMALINK, URL, SELFTEXT, SCORE];
+
+/// Columns read from comments, any other is ignored
+const COMMENT_COLUMNS: [&str; 8] = [ID, SUBREDDIT_ID, NSFW, CREATED, PERMALINK, BODY, SENTIMENT, SCORE];
+
+/// Columns read from comments when the file has them
+const OPTIONAL_COMMENT_COLUMNS: [&str; 1] = [LINK_ID];
+
+fn columns(file: FileKind) -> &'static [&'static str] {
+    match file {
+        FileKind::Posts => &POST_COLUMNS,
+        FileKind::Comments => &COMMENT_COLUMNS,
+    }
+}
+
+fn optional_columns(file: FileKind) -> &'static [&'static str] {
+    match file {
+        FileKind::Posts => &[],
+        FileKind::Comments => &OPTIONAL_COMMENT_COLUMNS,
+    }
+}
+
+/// Whether rows of `file` may lack `column`
+pub fn is_optional(file: FileKind, column: &str) -> bool {
+    optional_columns(file).contains(&column)
+}
+
+/// Names of the columns read from a file, optional ones last
+pub fn column_names(file: FileKind) -> Vec<&'static str> {
+    columns(file).iter().chain(optional_columns(file)).copied().collect()
+}
+
+/// Field names of the Pushshift dumps, accepted for their column without configuring them
+const PUSHSHIFT_ALIASES: [(&str, &str); 2] = [(SUBREDDIT_ID, "subreddit_id"), (NSFW, "over_18")];
+
+/// Other header names accepted for each column, parsed from `column=alias|alias,...`
+#[derive(Clone, Debug, Default)]
+pub struct ColumnAliases(HashMap<String, Vec<String>>);
+
+impl ColumnAliases {
+    /// Whether a header or field name stands for `column`
+    pub fn matches(&self, column: &str, name: &str) -> bool {
+        let name = name.trim();
+        name == column
+            || PUSHSHIFT_ALIASES.contains(&(column, name))
+            || self.0.get(column).is_some_and(|aliases| aliases.iter().any(|alias| alias == name))
+    }
+}
+
+impl FromStr for ColumnAliases {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut aliases = HashMap::new();
+        for entry in text.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (column, names) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid column alias {:?}, expected column=alias|alias", entry))?;
+            aliases
+                .entry(column.trim().to_string())
+                .or_insert_with(Vec::new)
+                .extend(names.split('|').map(|name| name.trim().to_string()));
+        }
+        Ok(Self(aliases))
+    }
+}
+
+/// Position in the header of each column read from a file
+#[derive(Clone, Debug)]
+pub struct ColumnMap {
+    file: FileKind,
+    /// Same order as the columns of the file
+    positions: Vec<usize>,
+    /// Same order as the optional columns of the file, None for the ones it lacks
+    optional_positions: Vec<Option<usize>>,
+}
+
+impl ColumnMap {
+    /// Fails listing every column the header lacks
+    pub fn new(file: FileKind, header: &StringRecord, aliases: &ColumnAliases) -> Result<Self, String> {
+        let mut positions = Vec::new();
+        let mut missing = Vec::new();
+        let position = |column: &&str| header.iter().position(|name| aliases.matches(column, name));
+        for column in columns(file) {
+            match position(column) {
+                Some(position) => positions.push(position),
+                None => missing.push(*column),
+            }
+        }
+        if !missing.is_empty() {
+            let header: Vec<&str> = header.iter().map(str::trim).collect();
+            return Err(format!("The {:?} header {:?} lacks the required columns {:?}", file, header, missing));
+        }
+        let optional_positions = optional_columns(file).iter().map(position).collect();
+        Ok(Self { file, positions, optional_positions })
+    }
+
+    /// Value of `column` in a row, None if the row is too short or the file lacks an optional
+    /// column
+    pub fn get<'r>(&self, record: &'r StringRecord, column: &str) -> Option<&'r str> {
+        match columns(self.file).iter().position(|known| *known == column) {
+            Some(index) => record.get(self.positions[index]),
+            None => {
+                let index = optional_columns(self.file).iter().position(|known| *known == column)?;
+                record.get(self.optional_positions[index]?)
+            }
+        }
+    }
+
+    /// Value of `column` in a row, rejecting rows too short to have it
+    pub fn value<'r>(&self, record: &'r StringRecord, column: &str) -> Result<&'r str, RejectReason> {
+        self.get(record, column).ok_or(RejectReason::Malformed)
+    }
+
+    /// Parses `column` in a row, None if it's empty
+    pub fn parse_optional<T: FromStr>(&self, record: &StringRecord, column: &str) -> Result<Option<T>, RejectReason> {
+        match self.value(record, column)?.trim() {
+            "" => Ok(None),
+            _ => self.parse(record, column).map(Some),
+        }
+    }
+
+    pub fn parse<T: FromStr>(&self, record: &StringRecord, column: &str) -> Result<T, RejectReason> {
+        self.value(record, column)?
+            .parse()
+            .map_err(|_| RejectReason::InvalidValue(column.to_string()))
+    }
+}
+
+/// Rows skipped while reading a file, counted by reason and optionally saved to a reject file
+/// as their line, reason and fields
+pub struct Rejects {
+    file: FileKind,
+    counts: BTreeMap<RejectReason, u64>,
+    writer: Option<Writer<File>>,
+}
+
+impl Rejects {
+    pub fn new(file: FileKind) -> Self {
+        Self { file, counts: BTreeMap::new(), writer: None }
+    }
+
+    pub fn save_to(&mut self, path: &str) -> io::Result<()> {
+        self.writer = Some(WriterBuilder::new().flexible(true).from_path(path)?);
+        Ok(())
+    }
+
+    /// `record` is None when the row couldn't be read at all
+    pub fn add(&mut self, line: u64, reason: RejectReason, record: Option<&StringRecord>) {
+        if let Some(writer) = self.writer.as_mut() {
+            let mut row = vec![line.to_string(), reason.to_string()];
+            row.extend(record.iter().flat_map(|record| record.iter()).map(str::to_string));
+            if let Err(e) = writer.write_record(&row).and_then(|_| Ok(writer.flush()?)) {
+                warn!("Failed to save rejected row {}: {:?}", line, e);
+            }
+        }
+        *self.counts.entry(reason).or_default() += 1;
+    }
+
+    pub fn totals(&self) -> Vec<RejectedRows> {
+        self.counts
+            .iter()
+            .map(|(reason, rows)| RejectedRows { file: self.file, reason: reason.clone(), rows: *rows })
+            .collect()
+    }
+}
+
+/// Validates the header of a file and the schema of up to `VALIDATED_ROWS` rows at its start,
+/// parsed like the producers parse them. A few bad rows are left for the producers to reject, the
+/// file only fails if none of them matches the schema. If `complete` is false more data follows
+/// `sample`, so its last row may be cut and is skipped
+pub fn validate(file: FileKind, sample: &[u8], complete: bool, format: InputFormat, aliases: &ColumnAliases) -> Result<(), String> {
+    let parse = match file {
+        FileKind::Posts => |record: &StringRecord, columns: &ColumnMap| Post::parse(record, columns).map(drop),
+        FileKind::Comments => |record: &StringRecord, columns: &ColumnMap| Comment::parse(record, columns).map(drop),
+    };
+    let mut rows = RowReader::new(file, sample, format, aliases)?;
+    let mut sampled = Vec::new();
+    let mut record = StringRecord::new();
+    while sampled.len() <= VALIDATED_ROWS {
+        match rows.read(&mut record) {
+            Ok(None) => break,
+            Ok(Some(line)) => sampled.push(parse(&record, rows.columns()).map_err(|reason| format!("Line {}: {}", line, reason))),
+            Err(RowError::Bad { line, error }) => sampled.push(Err(format!("Line {}: {}", line, error))),
+            Err(RowError::Missing { line, column }) => sampled.push(Err(format!("Line {}: missing {}", line, column))),
+            // A compressed sample ends in the middle of the stream
+            Err(RowError::Io(_)) if !complete => break,
+            Err(RowError::Io(e)) => return Err(e.to_string()),
+        }
+    }
+    if !complete {
+        sampled.pop();
+    }
+    sampled.truncate(VALIDATED_ROWS);
+    if sampled.iter().all(Result::is_err) {
+        if let Some(Err(first)) = sampled.into_iter().next() {
+            return Err(format!("None of the first rows matches the {:?} schema. {}", file, first));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,254 @@
+//! Score statistics that can be computed in parts and merged. Percentiles come from a sketch
+//! of logarithmic buckets, so they are approximate within `RELATIVE_ACCURACY` of the true value
+//! however many scores are added.
+
+use crate::protocol::ScoreSummary;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// Buckets of values whose magnitude is within `RELATIVE_ACCURACY` of each other
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Sketch {
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zeros: u64,
+}
+
+impl Sketch {
+    fn gamma() -> f64 {
+        (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+    }
+
+    fn bucket(magnitude: f64) -> i32 {
+        (magnitude.ln() / Self::gamma().ln()).ceil() as i32
+    }
+
+    fn bucket_value(bucket: i32) -> f64 {
+        2.0 * Self::gamma().powi(bucket) / (Self::gamma() + 1.0)
+    }
+
+    fn add(&mut self, value: f64) {
+        if value > 0.0 {
+            *self.positive.entry(Self::bucket(value)).or_default() += 1;
+        } else if value < 0.0 {
+            *self.negative.entry(Self::bucket(-value)).or_default() += 1;
+        } else {
+            self.zeros += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &Sketch) {
+        for (bucket, count) in &other.positive {
+            *self.positive.entry(*bucket).or_default() += count;
+        }
+        for (bucket, count) in &other.negative {
+            *self.negative.entry(*bucket).or_default() += count;
+        }
+        self.zeros += other.zeros;
+    }
+
+    /// Value of the bucket holding the value at `rank`, counting from the lowest
+    fn value_at(&self, rank: u64) -> f64 {
+        let negatives = self.negative.iter().rev().map(|(bucket, count)| (-Self::bucket_value(*bucket), *count));
+        let zeros = std::iter::once((0.0, self.zeros));
+        let positives = self.positive.iter().map(|(bucket, count)| (Self::bucket_value(*bucket), *count));
+        let mut seen = 0;
+        for (value, count) in negatives.chain(zeros).chain(positives) {
+            seen += count;
+            if seen > rank {
+                return value;
+            }
+        }
+        0.0
+    }
+}
+
+/// Count, mean and variance are kept as a running mean and sum of squared differences, which
+/// merge without overflowing or losing precision like a plain sum would
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ScoreStats {
+    count: u64,
+    mean: f64,
+    squared_diffs: f64,
+    min: f64,
+    max: f64,
+    sketch: Sketch,
+}
+
+impl ScoreStats {
+    pub fn add(&mut self, score: f64) {
+        let single = ScoreStats {
+            count: 1,
+            mean: score,
+            squared_diffs: 0.0,
+            min: score,
+            max: score,
+            sketch: Sketch::default(),
+        };
+        self.merge_moments(&single);
+        self.sketch.add(score);
+    }
+
+    pub fn merge(&mut self, other: &ScoreStats) {
+        self.merge_moments(other);
+        self.sketch.merge(&other.sketch);
+    }
+
+    fn merge_moments(&mut self, other: &ScoreStats) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            (self.min, self.max) = (other.min, other.max);
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.squared_diffs += other.squared_diffs + delta * delta * self.count as f64 * other.count as f64 / count as f64;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count = count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Approximate value below which a `quantile` fraction of the scores fall
+    pub fn quantile(&self, quantile: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let rank = (quantile.clamp(0.0, 1.0) * (self.count - 1) as f64).floor() as u64;
+        self.sketch.value_at(rank).clamp(self.min, self.max)
+    }
+
+    pub fn summary(&self) -> ScoreSummary {
+        if self.count == 0 {
+            return ScoreSummary::default();
+        }
+        let variance = self.squared_diffs / self.count as f64;
+        ScoreSummary {
+            count: self.count,
+            mean: self.mean,
+            variance,
+            stddev: variance.sqrt(),
+            min: self.min,
+            max: self.max,
+            median: self.quantile(0.5),
+            p90: self.quantile(0.9),
+            p99: self.quantile(0.99),
+        }
+    }
+}
+
+/// Statistic of a score summary used as a threshold
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Statistic {
+    #[default]
+    Mean,
+    Median,
+    P90,
+    P99,
+}
+
+impl Statistic {
+    pub fn of(&self, summary: &ScoreSummary) -> f64 {
+        match self {
+            Statistic::Mean => summary.mean,
+            Statistic::Median => summary.median,
+            Statistic::P90 => summary.p90,
+            Statistic::P99 => summary.p99,
+        }
+    }
+}
+
+impl FromStr for Statistic {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim() {
+            "mean" => Ok(Statistic::Mean),
+            "median" => Ok(Statistic::Median),
+            "p90" => Ok(Statistic::P90),
+            "p99" => Ok(Statistic::P99),
+            _ => Err(format!("Unknown statistic: {}", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_of(scores: impl IntoIterator<Item = f64>) -> ScoreStats {
+        let mut stats = ScoreStats::default();
+        for score in scores {
+            stats.add(score);
+        }
+        stats
+    }
+
+    fn assert_close(value: f64, expected: f64) {
+        let tolerance = RELATIVE_ACCURACY * expected.abs().max(1.0);
+        assert!((value - expected).abs() <= tolerance, "{} isn't close to {}", value, expected);
+    }
+
+    #[test]
+    fn summary_of_no_scores_is_empty() {
+        assert_eq!(ScoreStats::default().summary(), ScoreSummary::default());
+    }
+
+    #[test]
+    fn moments_are_exact() {
+        let summary = stats_of([2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]).summary();
+        assert_eq!(summary.count, 8);
+        assert_eq!(summary.mean, 5.0);
+        assert_eq!(summary.variance, 4.0);
+        assert_eq!(summary.stddev, 2.0);
+        assert_eq!((summary.min, summary.max), (2.0, 9.0));
+    }
+
+    #[test]
+    fn percentiles_are_within_the_accuracy() {
+        let summary = stats_of((1..=1000).map(f64::from)).summary();
+        assert_close(summary.median, 500.0);
+        assert_close(summary.p90, 900.0);
+        assert_close(summary.p99, 990.0);
+    }
+
+    #[test]
+    fn negative_and_zero_scores_are_ranked() {
+        let stats = stats_of([-10.0, -1.0, 0.0, 0.0, 3.0]);
+        assert_close(stats.quantile(0.0), -10.0);
+        assert_close(stats.quantile(0.25), -1.0);
+        assert_eq!(stats.quantile(0.5), 0.0);
+        assert_close(stats.quantile(1.0), 3.0);
+    }
+
+    #[test]
+    fn merged_parts_equal_the_whole() {
+        let scores: Vec<f64> = (0..100).map(|i| f64::from(i * 7 % 31) - 5.0).collect();
+        let whole = stats_of(scores.iter().copied()).summary();
+        let mut merged = stats_of(scores[..40].iter().copied());
+        merged.merge(&stats_of(scores[40..].iter().copied()));
+        merged.merge(&ScoreStats::default());
+        let merged = merged.summary();
+        assert_eq!(merged.count, whole.count);
+        assert_close(merged.mean, whole.mean);
+        assert_close(merged.variance, whole.variance);
+        assert_eq!((merged.min, merged.max), (whole.min, whole.max));
+        assert_eq!((merged.median, merged.p90, merged.p99), (whole.median, whole.p90, whole.p99));
+    }
+
+    #[test]
+    fn statistics_are_parsed_by_name() {
+        assert_eq!(" p90 ".parse::<Statistic>(), Ok(Statistic::P90));
+        assert!("p50".parse::<Statistic>().is_err());
+        let summary = ScoreSummary { mean: 1.0, median: 2.0, p90: 3.0, p99: 4.0, ..Default::default() };
+        assert_eq!(Statistic::Median.of(&summary), 2.0);
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Ranked<T> {
+    score: f32,
+    item: T,
+}
+
+impl<T: Ord> Ord for Ranked<T> {
+    /// Higher scores rank first, ties go to the lowest item so rankings are deterministic
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score).then_with(|| other.item.cmp(&self.item))
+    }
+}
+
+impl<T: Ord> PartialOrd for Ranked<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Ord> PartialEq for Ranked<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: Ord> Eq for Ranked<T> {}
+
+/// Keeps the `k` items with the highest scores seen, in a heap that never grows past `k`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopK<T: Ord> {
+    k: usize,
+    /// Min heap, so the lowest ranked item is the one evicted
+    heap: BinaryHeap<Reverse<Ranked<T>>>,
+}
+
+impl<T: Ord> Default for TopK<T> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<T: Ord> TopK<T> {
+    pub fn new(k: usize) -> Self {
+        Self {
+            k,
+            heap: BinaryHeap::with_capacity(k + 1),
+        }
+    }
+
+    pub fn push(&mut self, item: T, score: f32) {
+        self.heap.push(Reverse(Ranked { score, item }));
+        if self.heap.len() > self.k {
+            self.heap.pop();
+        }
+    }
+
+    /// Items in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.heap.iter().map(|Reverse(ranked)| &ranked.item)
+    }
+
+    /// Items with their scores, best first
+    pub fn into_ranking(self) -> Vec<(T, f32)> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(ranked)| (ranked.item, ranked.score))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_best_k_best_first() {
+        let mut top = TopK::new(3);
+        for (item, score) in [("a", 1.0), ("b", 5.0), ("c", 3.0), ("d", 4.0), ("e", 2.0)] {
+            top.push(item, score);
+        }
+        assert_eq!(top.iter().count(), 3);
+        assert_eq!(top.into_ranking(), [("b", 5.0), ("d", 4.0), ("c", 3.0)]);
+    }
+
+    #[test]
+    fn ties_go_to_the_lowest_item() {
+        let mut top = TopK::new(2);
+        for item in ["c", "a", "b"] {
+            top.push(item, 1.0);
+        }
+        assert_eq!(top.into_ranking(), [("a", 1.0), ("b", 1.0)]);
+    }
+
+    #[test]
+    fn empty_when_k_is_zero() {
+        let mut top = TopK::default();
+        top.push("a", 1.0);
+        assert!(top.into_ranking().is_empty());
+    }
+}